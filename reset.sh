@@ -1,4 +1,4 @@
 #!/bin/sh
-cargo run --release --bin dat2raw
+rm -f puzzlepuzzle.raw
 git checkout shape_db.json
 rm -f cached_groups.bin
@@ -1,4 +1,3 @@
 #!/bin/sh
 
-cp cached_groups.bin.bak cached_groups.bin
-cp puzzlepuzzle.raw.bak puzzlepuzzle.raw
+cargo run --release --bin checkpoint restore backup
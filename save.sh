@@ -1,4 +1,3 @@
 #!/bin/sh
 
-cp cached_groups.bin cached_groups.bin.bak
-cp puzzlepuzzle.raw puzzlepuzzle.raw.bak
+cargo run --release --bin checkpoint create backup --force
@@ -1,5 +1,5 @@
 #!/bin/sh
-cargo run --release --bin dat2raw
+rm -f puzzlepuzzle.raw
 git checkout shape_db.json
 rm -f cached_groups.bin
 cargo run --release --bin fix_bits
@@ -17,25 +17,23 @@ cargo run --release --bin solve 17245,8934..22200 --step-y 12
 
 cargo run --release --bin solve_trial $(for x in {0..184}; do echo $((6594 - (24 * $x))),83642; done)
 mv solution_0.raw puzzlepuzzle.raw
+mv solution_0.raw.hdr puzzlepuzzle.raw.hdr
 mv cached_groups_0.bin cached_groups.bin
 
-for end in 100 200 300 368; do
-  cargo run --release --bin solve_trial $(for x in $(seq 0 $end); do echo $((8850 - (24 * $x))),57086; done)
-  mv solution_0.raw puzzlepuzzle.raw
-  mv cached_groups_0.bin cached_groups.bin
-done
+cargo run --release --bin solve_trial $(for x in $(seq 0 368); do echo $((8850 - (24 * $x))),57086; done)
+mv solution_0.raw puzzlepuzzle.raw
+mv solution_0.raw.hdr puzzlepuzzle.raw.hdr
+mv cached_groups_0.bin cached_groups.bin
 
-for end in 100 200 300 368; do
-  cargo run --release --bin solve_trial $(for x in $(seq 0 $end); do echo $((8850 - (24 * $x))),39394; done)
-  mv solution_0.raw puzzlepuzzle.raw
-  mv cached_groups_0.bin cached_groups.bin
-done
+cargo run --release --bin solve_trial $(for x in $(seq 0 368); do echo $((8850 - (24 * $x))),39394; done)
+mv solution_0.raw puzzlepuzzle.raw
+mv solution_0.raw.hdr puzzlepuzzle.raw.hdr
+mv cached_groups_0.bin cached_groups.bin
 
-for end in 100 200 300 368; do
-  cargo run --release --bin solve_trial $(for x in $(seq 0 $end); do echo $((17238 - (24 * $x))),8866; done)
-  mv solution_0.raw puzzlepuzzle.raw
-  mv cached_groups_0.bin cached_groups.bin
-done
+cargo run --release --bin solve_trial $(for x in $(seq 0 368); do echo $((17238 - (24 * $x))),8866; done)
+mv solution_0.raw puzzlepuzzle.raw
+mv solution_0.raw.hdr puzzlepuzzle.raw.hdr
+mv cached_groups_0.bin cached_groups.bin
 
 cargo run --release --bin cat_flag
 
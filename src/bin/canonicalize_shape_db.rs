@@ -0,0 +1,145 @@
+//! Rewrites a shape database from before shapes were canonicalized, so that
+//! rotations and reflections of a shape share one entry and its solutions.
+
+use std::process::ExitCode;
+
+use clap::Parser;
+use tools::{Error, Orientation, Shape, ShapeDb, ShapeDbIndex, Workspace, canonicalize_group};
+
+#[derive(clap::Parser)]
+struct Args {
+    #[clap(flatten)]
+    workspace: Workspace,
+}
+
+fn main() -> ExitCode {
+    tools::report(run())
+}
+
+/// How an old shape maps onto the new database.
+struct Moved {
+    shape_id: usize,
+    /// Maps points of the old shape's frame to the new shape's frame.
+    anchor: (usize, usize),
+    orientation: Orientation,
+    /// New index of every old solution.
+    solution_ids: Vec<usize>,
+}
+
+impl Moved {
+    fn point(&self, (x, y): (usize, usize)) -> (usize, usize) {
+        // Inverse of `self.orientation.place(self.anchor, point)`.
+        let (x, y) = self.orientation.inverse().apply((
+            x as isize - self.anchor.0 as isize,
+            y as isize - self.anchor.1 as isize,
+        ));
+        (x as usize, y as usize)
+    }
+
+    fn points(&self, points: &[(usize, usize)]) -> Vec<(usize, usize)> {
+        let mut points = points
+            .iter()
+            .map(|&point| self.point(point))
+            .collect::<Vec<_>>();
+        points.sort_unstable();
+        points
+    }
+}
+
+fn run() -> tools::Result<()> {
+    let args = Args::parse();
+    let workspace = args.workspace;
+    let old_db = workspace.read_shape_db()?;
+
+    let mut new_db: ShapeDb = Vec::new();
+    let mut index = ShapeDbIndex::new();
+    let mut moved: Vec<Moved> = Vec::with_capacity(old_db.len());
+    for (old_id, shape) in old_db.iter().enumerate() {
+        // Children live in the frame of their parent, so they move with it.
+        let (mut frame, parent) = match shape.parent {
+            None => {
+                let (anchor, orientation, _) = canonicalize_group(&shape.group);
+                let frame = Moved {
+                    shape_id: 0,
+                    anchor,
+                    orientation,
+                    solution_ids: Vec::new(),
+                };
+                (frame, None)
+            }
+            Some(parent) => {
+                let Some(parent) = moved.get(parent) else {
+                    return Err(Error::corrupt(
+                        &workspace.shape_db_path(),
+                        format!("shape {old_id} comes before its parent {parent}"),
+                    ));
+                };
+                let frame = Moved {
+                    shape_id: 0,
+                    anchor: parent.anchor,
+                    orientation: parent.orientation,
+                    solution_ids: Vec::new(),
+                };
+                (frame, Some(parent))
+            }
+        };
+        let group = frame.points(&shape.group);
+        let solutions = shape
+            .solutions
+            .as_ref()
+            .map(|solutions| {
+                solutions
+                    .iter()
+                    .map(|solution| frame.points(solution))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        let used_solutions = shape.used_solutions.as_ref().map(|used_solutions| {
+            let parent = parent.unwrap();
+            used_solutions
+                .iter()
+                .map(|&solution_id| parent.solution_ids[solution_id])
+                .collect::<Vec<_>>()
+        });
+        let key = (group, parent.map(|parent| parent.shape_id));
+
+        frame.shape_id = *index.entry(key.clone()).or_insert_with(|| {
+            new_db.push(Shape {
+                group: key.0,
+                solutions: None,
+                parent: key.1,
+                used_solutions,
+            });
+            new_db.len() - 1
+        });
+        let new_shape = &mut new_db[frame.shape_id];
+        if shape.solutions.is_some() {
+            let new_solutions = new_shape.solutions.get_or_insert_with(Vec::new);
+            let had_solutions = !new_solutions.is_empty();
+            for solution in solutions {
+                let solution_id = match new_solutions.iter().position(|known| *known == solution) {
+                    Some(solution_id) => solution_id,
+                    None => {
+                        if had_solutions {
+                            println!(
+                                "Shape {old_id} has a solution that shape {} did not have",
+                                frame.shape_id
+                            );
+                        }
+                        new_solutions.push(solution);
+                        new_solutions.len() - 1
+                    }
+                };
+                frame.solution_ids.push(solution_id);
+            }
+        }
+        moved.push(frame);
+    }
+
+    println!(
+        "Rewrote {} shapes as {} canonical shapes",
+        old_db.len(),
+        new_db.len()
+    );
+    workspace.write_shape_db(&new_db)
+}
@@ -1,22 +1,56 @@
-use core::panic;
+use std::process::ExitCode;
 
-fn main() {
-    let map = tools::read_map();
+use clap::Parser;
+use tools::{Error, Tile, Workspace};
+
+/// The row the flag is written in, and the columns of its bits.
+const FLAG_ROW: usize = 5;
+const FLAG_COLUMNS: std::ops::Range<usize> = 8427..17236;
+
+#[derive(clap::Parser)]
+struct Args {
+    #[clap(flatten)]
+    workspace: Workspace,
+}
+
+fn main() -> ExitCode {
+    tools::report(run())
+}
+
+fn run() -> tools::Result<()> {
+    let args = Args::parse();
+    let mut map_file = args.workspace.open_map_file()?;
+    if map_file.width() < FLAG_COLUMNS.end || map_file.height() <= FLAG_ROW {
+        return Err(Error::InvalidArgument(format!(
+            "the flag needs a map of at least {}x{}, but {} is {}x{}",
+            FLAG_COLUMNS.end,
+            FLAG_ROW + 1,
+            map_file.path().display(),
+            map_file.width(),
+            map_file.height()
+        )));
+    }
+    let mut row = Vec::new();
+    map_file.read_row(FLAG_ROW, &mut row)?;
     let mut bits = Vec::new();
-    for x in (8427..17236).step_by(24).rev() {
-        if map[5][x] == tools::ACTIVE {
-            bits.push(true)
-        } else if map[5][x] == tools::NOT_ACTIVE {
-            bits.push(false);
-        } else {
-            panic!("Unexpected tile value at (5, {}): {}", x, map[5][x]);
+    for x in FLAG_COLUMNS.step_by(24).rev() {
+        match Tile::try_from(row[x]) {
+            Ok(Tile::Active) => bits.push(true),
+            Ok(Tile::NotActive) => bits.push(false),
+            _ => {
+                return Err(Error::corrupt(
+                    map_file.path(),
+                    format!("unexpected tile value at ({x}, {FLAG_ROW}): {}", row[x]),
+                ));
+            }
         }
     }
-    assert!(
-        bits.len() % 8 == 0,
-        "Bit length is not a multiple of 8: {}",
-        bits.len()
-    );
+    if bits.len() % 8 != 0 {
+        return Err(Error::InvalidArgument(format!(
+            "the flag has {} bits, which is not a whole number of bytes",
+            bits.len()
+        )));
+    }
     print!("CTF{{");
     for chunk in bits.chunks(8).rev() {
         let byte = chunk
@@ -27,4 +61,5 @@ fn main() {
         print!("{}", byte as char);
     }
     println!("}}");
+    Ok(())
 }
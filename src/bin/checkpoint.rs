@@ -0,0 +1,70 @@
+use std::process::ExitCode;
+
+use clap::Parser;
+use tools::{
+    Error, Workspace,
+    checkpoint::{CHECKPOINT_DIR, Checkpoint, checkpoint_path, list_checkpoints},
+};
+
+#[derive(clap::Parser)]
+struct Args {
+    #[clap(subcommand)]
+    command: Command,
+    #[clap(flatten)]
+    workspace: Workspace,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// List the saved checkpoints
+    List,
+    /// Save the map, shape database and cached groups
+    Create {
+        name: String,
+        /// Overwrite an existing checkpoint with the same name
+        #[clap(long)]
+        force: bool,
+    },
+    /// Replace the map, shape database and cached groups with a checkpoint
+    Restore { name: String },
+}
+
+fn main() -> ExitCode {
+    tools::report(run())
+}
+
+fn run() -> tools::Result<()> {
+    let args = Args::parse();
+    let workspace = args.workspace;
+    match args.command {
+        Command::List => {
+            for (name, meta) in list_checkpoints(&workspace)? {
+                println!("{name}");
+                println!("  created:       {}", meta.created);
+                println!("  command line:  {}", meta.command_line.join(" "));
+                println!("  map:           {}x{}", meta.width, meta.height);
+                println!("  map hash:      {:016x}", meta.map_hash);
+                println!("  shape db hash: {:016x}", meta.shape_db_hash);
+                match meta.cached_groups_hash {
+                    Some(hash) => println!("  cache hash:    {hash:016x}"),
+                    None => println!("  cache hash:    (no cache)"),
+                }
+            }
+        }
+        Command::Create { name, force } => {
+            let path = checkpoint_path(&workspace, &name);
+            if !force && tools::exists(&path)? {
+                return Err(Error::InvalidArgument(format!(
+                    "checkpoint {name} already exists, use --force to overwrite it"
+                )));
+            }
+            let dir = workspace.path(CHECKPOINT_DIR);
+            std::fs::create_dir_all(&dir).map_err(|source| Error::Write { path: dir, source })?;
+            Checkpoint::capture(&workspace)?.write(&path)?;
+        }
+        Command::Restore { name } => {
+            Checkpoint::read(&checkpoint_path(&workspace, &name))?.restore(&workspace)?;
+        }
+    }
+    Ok(())
+}
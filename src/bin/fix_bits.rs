@@ -1,9 +1,23 @@
-use tools::write_map;
+use std::process::ExitCode;
 
-fn main() {
-    let mut map = tools::read_map();
+use clap::Parser;
+use tools::{Tile, Workspace};
+
+#[derive(clap::Parser)]
+struct Args {
+    #[clap(flatten)]
+    workspace: Workspace,
+}
+
+fn main() -> ExitCode {
+    tools::report(run())
+}
+
+fn run() -> tools::Result<()> {
+    let args = Args::parse();
+    let mut map = args.workspace.read_map()?;
     for x in (8426..17235).step_by(24).rev() {
-        map[5][x] = tools::UNPROCESSED;
+        map.set(x, 5, Tile::Unprocessed);
     }
-    write_map(&map);
+    args.workspace.write_map(&map)
 }
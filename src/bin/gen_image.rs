@@ -2,7 +2,8 @@ use clap::Parser;
 use image::{ImageEncoder, codecs::png::PngEncoder};
 use std::fs::File;
 use std::io::BufWriter;
-use tools::{H, W, read_map};
+use std::process::ExitCode;
+use tools::{Error, Workspace};
 
 #[derive(clap::Parser)]
 struct Args {
@@ -10,40 +11,51 @@ struct Args {
     x2: usize,
     y1: usize,
     y2: usize,
+    #[clap(flatten)]
+    workspace: Workspace,
 }
 
-fn main() {
+fn main() -> ExitCode {
+    tools::report(run())
+}
+
+fn run() -> tools::Result<()> {
     let args = Args::parse();
-    let map = read_map();
+    let map = args.workspace.read_map()?;
+
+    if args.x1 >= map.width() || args.y1 >= map.height() || args.x2 <= args.x1 || args.y2 <= args.y1
+    {
+        return Err(Error::InvalidArgument(format!(
+            "({}, {})..({}, {}) does not overlap the {}x{} map",
+            args.x1,
+            args.y1,
+            args.x2,
+            args.y2,
+            map.width(),
+            map.height()
+        )));
+    }
 
-    let width = (args.x2 - args.x1).min(W - args.x1);
-    let height = (args.y2 - args.y1).min(H - args.y1);
+    let width = (args.x2 - args.x1).min(map.width() - args.x1);
+    let height = (args.y2 - args.y1).min(map.height() - args.y1);
 
     let mut img = vec![0; width * height * 3];
 
     for y in 0..height {
         for x in 0..width {
-            let pixel = match map[(args.y1 + y) as usize][(args.x1 + x) as usize] {
-                0 => [0, 0, 0],
-                1 => [255, 0, 0],
-                2 => [255, 255, 0],
-                3 => [0, 255, 0],
-                5 => [255, 255, 255],
-                7 => [128, 128, 128],
-                8 => [231, 141, 14],
-                10 => [255, 0, 255],
-                _ => [0, 0, 255],
-                // n => todo!("{n}"),
-            };
-            img[(y * width + x) * 3 + 0] = pixel[0];
+            let pixel = map.get(args.x1 + x, args.y1 + y).color();
+            img[(y * width + x) * 3] = pixel[0];
             img[(y * width + x) * 3 + 1] = pixel[1];
             img[(y * width + x) * 3 + 2] = pixel[2];
         }
     }
 
     // Save the image to a file
-    let path = "output_image.png";
-    let file = File::create(path).expect("Failed to create image file");
+    let path = args.workspace.path("output_image.png");
+    let file = File::create(&path).map_err(|source| Error::Write {
+        path: path.clone(),
+        source,
+    })?;
     let writer = PngEncoder::new_with_quality(
         BufWriter::new(file),
         image::codecs::png::CompressionType::Best,
@@ -57,7 +69,11 @@ fn main() {
             height as u32,
             image::ExtendedColorType::Rgb8,
         )
-        .expect("Failed to save image");
+        .map_err(|source| Error::Image {
+            path: path.clone(),
+            source,
+        })?;
 
-    println!("Image saved to {}", path);
+    println!("Image saved to {}", path.display());
+    Ok(())
 }
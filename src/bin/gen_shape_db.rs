@@ -1,39 +1,46 @@
-use std::{
-    collections::{HashMap, HashSet},
-    sync::Mutex,
-};
+use std::{collections::HashSet, process::ExitCode, sync::Mutex};
 
+use clap::Parser;
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
-use tools::{H, Map, W, normalize_group, read_map};
+use tools::{
+    Workspace, canonicalize_group,
+    labels::{ComponentId, Labels},
+};
+
+#[derive(clap::Parser)]
+struct Args {
+    #[clap(flatten)]
+    workspace: Workspace,
+}
+
+fn main() -> ExitCode {
+    tools::report(run())
+}
 
-fn main() {
-    let map = read_map();
+fn run() -> tools::Result<()> {
+    let args = Args::parse();
+    let map = args.workspace.read_map()?;
 
-    let normalized_group_types = Mutex::new(HashSet::new());
+    let labels = Labels::compute(&map);
+    println!("Found {} groups", labels.component_count());
 
-    (0..H)
+    let canonical_group_types = Mutex::new(HashSet::new());
+    (0..labels.component_count() as ComponentId)
         .into_par_iter()
-        .for_each_with(HashSet::new(), |used, y| {
-            if y % 5000 == 0 {
-                println!("Processing row {y} of {H}");
-            }
-            for x in 0..W {
-                if let Some((group, _neighbors)) = find_group(&map, x, y, used) {
-                    let (_, _, normalized_group) = normalize_group(&group);
-                    normalized_group_types
-                        .lock()
-                        .unwrap()
-                        .insert(normalized_group);
-                }
-            }
+        .for_each(|component| {
+            let (_, _, canonical_group) = canonicalize_group(&labels.cells(component));
+            canonical_group_types
+                .lock()
+                .unwrap()
+                .insert(canonical_group);
         });
 
-    let normalized_group_types = normalized_group_types.into_inner().unwrap();
-    let mut normalized_group_types: Vec<Vec<(usize, usize)>> =
-        normalized_group_types.into_iter().collect();
-    normalized_group_types.sort_unstable();
+    let canonical_group_types = canonical_group_types.into_inner().unwrap();
+    let mut canonical_group_types: Vec<Vec<(usize, usize)>> =
+        canonical_group_types.into_iter().collect();
+    canonical_group_types.sort_unstable();
 
-    let shape_db: tools::ShapeDb = normalized_group_types
+    let shape_db: tools::ShapeDb = canonical_group_types
         .into_iter()
         .map(|group| tools::Shape {
             group,
@@ -43,42 +50,5 @@ fn main() {
         })
         .collect();
 
-    std::fs::write("shape_db.json", serde_json::to_string(&shape_db).unwrap()).unwrap();
-}
-
-pub fn find_group(
-    map: &Map,
-    x: usize,
-    y: usize,
-    used: &mut HashSet<(usize, usize)>,
-) -> Option<(Vec<(usize, usize)>, HashMap<(usize, usize), u8>)> {
-    let mut group = Vec::new();
-    let mut stack = vec![(x, y)];
-    let mut neighbors = HashMap::new();
-    let tile = map[y][x];
-    if tile != 5 || used.contains(&(x, y)) {
-        return None;
-    }
-
-    while let Some((cx, cy)) = stack.pop() {
-        for (nx, ny) in [
-            (cx.wrapping_sub(1), cy), // left
-            (cx + 1, cy),             // right
-            (cx, cy.wrapping_sub(1)), // up
-            (cx, cy + 1),             // down
-        ] {
-            if nx < W && ny < H {
-                if map[ny][nx] == 5 {
-                    if used.insert((nx, ny)) {
-                        group.push((nx, ny));
-                        stack.push((nx, ny));
-                    }
-                } else if map[ny][nx] != 0 {
-                    neighbors.insert((nx, ny), map[ny][nx]);
-                }
-            }
-        }
-    }
-
-    Some((group, neighbors))
+    args.workspace.write_shape_db(&shape_db)
 }
@@ -0,0 +1,155 @@
+use std::{
+    collections::{HashMap, HashSet},
+    process::ExitCode,
+    sync::Mutex,
+};
+
+use clap::Parser;
+use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use tools::{
+    Error, ShapeId, Workspace, canonicalize_group, clue_context, enumerate_solutions,
+    index_shape_db,
+    labels::{ComponentId, Labels},
+};
+
+#[derive(clap::Parser)]
+struct Args {
+    /// Shapes to generate solutions for, replacing the ones they have.
+    /// Defaults to every shape without solutions.
+    shape_ids: Vec<ShapeId>,
+    /// Skip shapes with more tiles than this.
+    #[clap(long, default_value = "20")]
+    max_cells: usize,
+    /// Skip shapes with more candidate solutions than this.
+    #[clap(long, default_value = "32")]
+    max_solutions: usize,
+    #[clap(flatten)]
+    workspace: Workspace,
+}
+
+fn main() -> ExitCode {
+    tools::report(run())
+}
+
+fn run() -> tools::Result<()> {
+    let args = Args::parse();
+    let map = args.workspace.read_map()?;
+    let mut shape_db = args.workspace.read_shape_db()?;
+    let shape_db_index = index_shape_db(&shape_db);
+
+    // Children refer to the solutions of their parent by index, so those
+    // must not be replaced.
+    let parents = shape_db
+        .iter()
+        .filter_map(|shape| shape.parent)
+        .collect::<HashSet<_>>();
+    let selected = if args.shape_ids.is_empty() {
+        (0..shape_db.len())
+            .filter(|&shape_id| {
+                let shape = &shape_db[shape_id];
+                shape.solutions.is_none() && shape.parent.is_none() && !parents.contains(&shape_id)
+            })
+            .collect::<HashSet<_>>()
+    } else {
+        for &shape_id in &args.shape_ids {
+            let Some(shape) = shape_db.get(shape_id) else {
+                return Err(Error::InvalidArgument(format!(
+                    "shape ID {shape_id} out of bounds ({} shapes)",
+                    shape_db.len()
+                )));
+            };
+            if let Some(parent) = shape.parent {
+                return Err(Error::InvalidArgument(format!(
+                    "shape {shape_id} is part of shape {parent}, generate solutions for that one instead"
+                )));
+            }
+            if parents.contains(&shape_id) {
+                return Err(Error::InvalidArgument(format!(
+                    "shape {shape_id} has child shapes that refer to its solutions, so they cannot be replaced"
+                )));
+            }
+        }
+        args.shape_ids.iter().copied().collect()
+    };
+
+    let labels = Labels::compute(&map);
+    println!("Found {} groups", labels.component_count());
+
+    // The distinct clue contexts every selected shape occurs in, and how
+    // often it occurs.
+    let occurrences = Mutex::new(HashMap::<ShapeId, (HashSet<_>, usize)>::new());
+    let unknown_groups = Mutex::new(0);
+    (0..labels.component_count() as ComponentId)
+        .into_par_iter()
+        .for_each(|component| {
+            let (anchor, orientation, canonical_group) =
+                canonicalize_group(&labels.cells(component));
+            let Some(&shape_id) = shape_db_index.get(&(canonical_group, None)) else {
+                *unknown_groups.lock().unwrap() += 1;
+                return;
+            };
+            if !selected.contains(&shape_id) {
+                return;
+            }
+            let context = clue_context(&map, &shape_db[shape_id], anchor, orientation);
+            let mut occurrences = occurrences.lock().unwrap();
+            let (contexts, count) = occurrences.entry(shape_id).or_default();
+            contexts.insert(context);
+            *count += 1;
+        });
+    let unknown_groups = unknown_groups.into_inner().unwrap();
+    if unknown_groups > 0 {
+        eprintln!(
+            "warning: {unknown_groups} groups are not in the shape database, run gen_shape_db to add them"
+        );
+    }
+    let occurrences = occurrences.into_inner().unwrap();
+
+    let mut selected = selected.into_iter().collect::<Vec<_>>();
+    selected.sort_unstable();
+    let generated = selected
+        .par_iter()
+        .map(|&shape_id| {
+            let group = &shape_db[shape_id].group;
+            let Some((contexts, count)) = occurrences.get(&shape_id) else {
+                return (shape_id, Err("does not occur in the map".to_string()));
+            };
+            if group.len() > args.max_cells {
+                return (shape_id, Err(format!("has {} tiles, skipped", group.len())));
+            }
+            let contexts = contexts.iter().cloned().collect::<Vec<_>>();
+            let result = match enumerate_solutions(group, &contexts, args.max_solutions) {
+                None => Err(format!(
+                    "has more than {} candidate solutions, skipped",
+                    args.max_solutions
+                )),
+                Some(solutions) if solutions.is_empty() => Err(format!(
+                    "has no solution that fits any of its {count} occurrences"
+                )),
+                Some(solutions) => Ok((solutions, *count, contexts.len())),
+            };
+            (shape_id, result)
+        })
+        .collect::<Vec<_>>();
+
+    let mut generated_count = 0;
+    for (shape_id, result) in generated {
+        match result {
+            Ok((solutions, count, patterns)) => {
+                println!(
+                    "Shape {shape_id}: {} solutions from {count} occurrences in {patterns} clue patterns",
+                    solutions.len()
+                );
+                shape_db[shape_id].solutions = Some(solutions);
+                generated_count += 1;
+            }
+            Err(reason) => println!("Shape {shape_id} {reason}"),
+        }
+    }
+    if generated_count == 0 {
+        println!("No solutions generated");
+        return Ok(());
+    }
+    println!("Generated solutions for {generated_count} shapes, review them with show_shape");
+    args.workspace.write_shape_db(&shape_db)
+}
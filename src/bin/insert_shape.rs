@@ -1,76 +1,79 @@
-use std::collections::BTreeSet;
+use std::{collections::BTreeSet, process::ExitCode};
 
 use clap::Parser;
-use tools::{SHAPE_ALPHABET, Shape, Solution, read_shape_db, show_shape, write_shape_db};
+use tools::{Error, SHAPE_ALPHABET, Shape, Solution, Workspace, show_shape};
 
 #[derive(clap::Parser)]
 struct Args {
     shape_id: usize,
     solutions: Vec<String>,
+    #[clap(flatten)]
+    workspace: Workspace,
 }
 
-fn main() {
+fn main() -> ExitCode {
+    tools::report(run())
+}
+
+fn run() -> tools::Result<()> {
     let args = Args::parse();
     let shape_id = args.shape_id;
-    let mut shape_db: tools::ShapeDb = read_shape_db();
+    let mut shape_db: tools::ShapeDb = args.workspace.read_shape_db()?;
 
     if shape_id >= shape_db.len() {
-        eprintln!(
-            "Shape ID {} out of bounds (max {})",
-            shape_id,
-            shape_db.len() - 1
-        );
-        return;
+        return Err(Error::InvalidArgument(format!(
+            "shape ID {shape_id} out of bounds ({} shapes)",
+            shape_db.len()
+        )));
     }
 
     let solutions = args.solutions;
-    let sol_unique = solutions
-        .iter()
-        .map(|sol| {
-            assert!(sol.chars().all(|c| tools::SHAPE_ALPHABET.contains(c)));
-            let sol_unique = sol.chars().collect::<BTreeSet<char>>();
-            assert_eq!(
-                sol.len(),
-                sol_unique.len(),
-                "Duplicate characters in solution: {sol}"
-            );
-            sol_unique
-        })
-        .collect::<BTreeSet<BTreeSet<char>>>();
-
-    assert_eq!(
-        sol_unique.len(),
-        solutions.len(),
-        "Duplicate solutions found: {:?}",
-        sol_unique
-    );
+    let mut sol_unique = BTreeSet::new();
+    for sol in &solutions {
+        if let Some(c) = sol.chars().find(|&c| !SHAPE_ALPHABET.contains(c)) {
+            return Err(Error::InvalidArgument(format!(
+                "invalid character {c:?} in solution {sol}"
+            )));
+        }
+        let chars = sol.chars().collect::<BTreeSet<char>>();
+        if chars.len() != sol.len() {
+            return Err(Error::InvalidArgument(format!(
+                "duplicate characters in solution {sol}"
+            )));
+        }
+        if !sol_unique.insert(chars) {
+            return Err(Error::InvalidArgument(format!("duplicate solution {sol}")));
+        }
+    }
 
     let shape = &mut shape_db[shape_id];
-    show_shape(&shape);
+    show_shape(shape);
     println!();
     println!("You entered:");
 
     let solutions = solutions
         .into_iter()
-        .map(|sol| solution_string_to_solution(&sol, &shape))
-        .collect::<Vec<Solution>>();
+        .map(|sol| solution_string_to_solution(&sol, shape))
+        .collect::<tools::Result<Vec<Solution>>>()?;
     shape.solutions = Some(solutions);
 
     println!("Correct?");
     let is_correct = std::io::stdin()
         .lines()
         .next()
-        .expect("Failed to read input")
-        .expect("Failed to read line")
+        .transpose()
+        .map_err(Error::read(std::path::Path::new("<stdin>")))?
+        .unwrap_or_default()
         .trim()
         .starts_with('y');
 
     if is_correct {
-        write_shape_db(&shape_db);
+        args.workspace.write_shape_db(&shape_db)?;
     }
+    Ok(())
 }
 
-fn solution_string_to_solution(solutions: &str, shape: &Shape) -> Solution {
+fn solution_string_to_solution(solutions: &str, shape: &Shape) -> tools::Result<Solution> {
     let mut solution = solutions.chars().collect::<BTreeSet<char>>();
     let max_x = shape.group.iter().map(|(x, _)| *x).max().unwrap();
     let max_y = shape.group.iter().map(|(_, y)| *y).max().unwrap();
@@ -94,11 +97,12 @@ fn solution_string_to_solution(solutions: &str, shape: &Shape) -> Solution {
         println!()
     }
     println!();
-    assert!(
-        solution.is_empty(),
-        "Not all letters used in solution: {:?}",
-        solution
-    );
+    if !solution.is_empty() {
+        return Err(Error::InvalidArgument(format!(
+            "letters {:?} are not part of the shape",
+            solution
+        )));
+    }
 
-    out
+    Ok(out)
 }
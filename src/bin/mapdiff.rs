@@ -0,0 +1,40 @@
+use std::{path::PathBuf, process::ExitCode};
+
+use clap::Parser;
+use tools::{MapDiff, MapFile, Workspace, write_map_diff};
+
+#[derive(clap::Parser)]
+struct Args {
+    old: PathBuf,
+    /// Defaults to the working map
+    new: Option<PathBuf>,
+    #[clap(long, short, default_value = "puzzlepuzzle.patch")]
+    output: PathBuf,
+    /// Print every changed tile
+    #[clap(long)]
+    verbose: bool,
+    #[clap(flatten)]
+    workspace: Workspace,
+}
+
+fn main() -> ExitCode {
+    tools::report(run())
+}
+
+fn run() -> tools::Result<()> {
+    let args = Args::parse();
+    let workspace = args.workspace;
+    let new = args
+        .new
+        .map_or_else(|| workspace.map_path(), |new| workspace.path(new));
+    let mut old = MapFile::open(&workspace.path(args.old), false)?;
+    let mut new = MapFile::open(&new, false)?;
+    let diff = MapDiff::between(&mut old, &mut new)?;
+
+    if args.verbose {
+        for &((x, y), old_value, new_value) in &diff.changes {
+            println!("({x}, {y}): {} -> {}", old_value as u8, new_value as u8);
+        }
+    }
+    write_map_diff(&diff, &workspace.path(args.output))
+}
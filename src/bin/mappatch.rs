@@ -0,0 +1,38 @@
+use std::{path::PathBuf, process::ExitCode};
+
+use clap::Parser;
+use tools::{MapFile, Workspace, read_map_diff};
+
+#[derive(clap::Parser)]
+struct Args {
+    patch: PathBuf,
+    /// Undo the patch instead of applying it
+    #[clap(long)]
+    revert: bool,
+    #[clap(flatten)]
+    workspace: Workspace,
+}
+
+fn main() -> ExitCode {
+    tools::report(run())
+}
+
+fn run() -> tools::Result<()> {
+    let args = Args::parse();
+    let workspace = args.workspace;
+    let mut diff = read_map_diff(&workspace.path(args.patch))?;
+    if args.revert {
+        diff = diff.reversed();
+    }
+
+    let map_path = workspace.map_path();
+    let mut map_file = MapFile::open(&map_path, true)?;
+    diff.apply(&mut map_file)?;
+    println!(
+        "{} {} changes to {}",
+        if args.revert { "Reverted" } else { "Applied" },
+        diff.changes.len(),
+        map_path.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,29 @@
+use std::{path::PathBuf, process::ExitCode};
+
+use clap::Parser;
+use tools::Workspace;
+
+#[derive(clap::Parser)]
+struct Args {
+    /// Defaults to the working map, or the original puzzle if there is none
+    /// yet
+    input: Option<PathBuf>,
+    #[clap(default_value = "solved.dat")]
+    output: PathBuf,
+    #[clap(flatten)]
+    workspace: Workspace,
+}
+
+fn main() -> ExitCode {
+    tools::report(run())
+}
+
+fn run() -> tools::Result<()> {
+    let args = Args::parse();
+    let workspace = args.workspace;
+    let map = match args.input {
+        Some(input) => tools::read_map_file(&workspace.path(input))?,
+        None => workspace.read_map()?,
+    };
+    tools::write_dat(&map, &workspace.path(args.output))
+}
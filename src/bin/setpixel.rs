@@ -1,16 +1,45 @@
+use std::process::ExitCode;
+
 use clap::Parser;
-use tools::read_map;
+use tools::{Error, MapFile, Tile, Workspace};
 
 #[derive(clap::Parser)]
 struct Args {
     x: usize,
     y: usize,
     value: u8,
+    #[clap(flatten)]
+    workspace: Workspace,
+}
+
+fn main() -> ExitCode {
+    tools::report(run())
 }
 
-fn main() {
+fn run() -> tools::Result<()> {
     let args = Args::parse();
-    let mut map = read_map();
-    map[args.y][args.x] = args.value;
-    tools::write_map(&map);
+    let workspace = args.workspace;
+    let tile = Tile::try_from(args.value)
+        .map_err(|invalid| Error::InvalidArgument(invalid.to_string()))?;
+    let map_path = workspace.map_path();
+    if tools::exists(&map_path)? {
+        let mut map_file = MapFile::open(&map_path, true)?;
+        map_file.set(args.x, args.y, tile)?;
+        println!("Patched {}", map_path.display());
+    } else {
+        // Never patch the pristine puzzle, start a working map instead.
+        let mut map = workspace.read_map()?;
+        if !map.in_bounds(args.x, args.y) {
+            return Err(Error::InvalidArgument(format!(
+                "({}, {}) is outside of the {}x{} map",
+                args.x,
+                args.y,
+                map.width(),
+                map.height()
+            )));
+        }
+        map.set(args.x, args.y, tile);
+        workspace.write_map(&map)?;
+    }
+    Ok(())
 }
@@ -0,0 +1,554 @@
+use std::{collections::HashMap, process::ExitCode};
+
+use clap::Parser;
+use tools::{Error, Shape, ShapeDb, ShapeId, Solution, Workspace, canonicalize_group};
+
+#[derive(clap::Parser)]
+struct Args {
+    #[clap(subcommand)]
+    command: Command,
+    #[clap(flatten)]
+    workspace: Workspace,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Check the shape database for inconsistencies
+    Fsck {
+        /// Fix the problems that can be fixed and write the database back
+        #[clap(long)]
+        repair: bool,
+    },
+}
+
+fn main() -> ExitCode {
+    tools::report(run())
+}
+
+fn run() -> tools::Result<()> {
+    let args = Args::parse();
+    let workspace = args.workspace;
+    match args.command {
+        Command::Fsck { repair } => {
+            let mut shape_db = workspace.read_shape_db()?;
+            let problems = check(&shape_db);
+            for problem in &problems {
+                println!("{problem}");
+            }
+            let repairable = problems.iter().filter(|problem| problem.repairable).count();
+            println!(
+                "Found {} problems, {repairable} of them repairable",
+                problems.len()
+            );
+
+            let mut remaining = problems.len();
+            if repair && repairable > 0 {
+                let shape_count = shape_db.len();
+                for problem in repair_shape_db(&mut shape_db) {
+                    println!("{problem}");
+                }
+                remaining = check(&shape_db).len();
+                println!(
+                    "Repaired {} problems",
+                    problems.len().saturating_sub(remaining)
+                );
+                if shape_db.len() != shape_count {
+                    println!("Shape IDs changed, so the cached groups will have to be rebuilt");
+                }
+                workspace.write_shape_db(&shape_db)?;
+            }
+            if remaining > 0 {
+                let hint = if repair || repairable == 0 {
+                    ""
+                } else {
+                    ", run with --repair to fix some of them"
+                };
+                return Err(Error::corrupt(
+                    &workspace.shape_db_path(),
+                    format!("{remaining} problems found{hint}"),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+struct Problem {
+    shape_id: ShapeId,
+    message: String,
+    repairable: bool,
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "shape {}: {}", self.shape_id, self.message)?;
+        if self.repairable {
+            write!(f, " (repairable)")?;
+        }
+        Ok(())
+    }
+}
+
+fn check(shape_db: &[Shape]) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut problem = |shape_id, repairable, message: String| {
+        problems.push(Problem {
+            shape_id,
+            message,
+            repairable,
+        })
+    };
+
+    let mut keys = HashMap::new();
+    for (shape_id, shape) in shape_db.iter().enumerate() {
+        if shape.group.is_empty() {
+            problem(shape_id, false, "has an empty group".to_string());
+            continue;
+        }
+
+        if let Some(solutions) = &shape.solutions {
+            let mut seen = Vec::new();
+            for (solution_id, solution) in solutions.iter().enumerate() {
+                if let Some(point) = solution.iter().find(|point| !shape.group.contains(point)) {
+                    // Dropping the solution could rule out what actually
+                    // happens at the shape, so it needs a look by hand.
+                    problem(
+                        shape_id,
+                        false,
+                        format!("solution {solution_id} has {point:?}, which is not in the group"),
+                    );
+                }
+                let sorted_solution = sorted(solution);
+                if let Some(first) = seen.iter().position(|known| *known == sorted_solution) {
+                    problem(
+                        shape_id,
+                        true,
+                        format!("solution {solution_id} is the same as solution {first}"),
+                    );
+                }
+                seen.push(sorted_solution);
+            }
+        }
+
+        match shape.parent {
+            None => {
+                let (_, _, canonical) = canonicalize_group(&shape.group);
+                if canonical != sorted(&shape.group) {
+                    problem(
+                        shape_id,
+                        false,
+                        "group is not canonical, run canonicalize_shape_db".to_string(),
+                    );
+                }
+                if shape.used_solutions.is_some() {
+                    problem(
+                        shape_id,
+                        true,
+                        "has used solutions but no parent".to_string(),
+                    );
+                }
+            }
+            Some(parent_id) => {
+                if let Some(message) = broken_parent(shape_db, shape_id) {
+                    problem(shape_id, false, message);
+                } else {
+                    let parent = &shape_db[parent_id];
+                    if let Some(point) = shape
+                        .group
+                        .iter()
+                        .find(|point| !parent.group.contains(point))
+                    {
+                        problem(
+                            shape_id,
+                            false,
+                            format!("{point:?} is not in the group of parent {parent_id}"),
+                        );
+                    }
+                    let parent_solutions = parent.solutions.as_ref().map_or(0, Vec::len);
+                    match &shape.used_solutions {
+                        None => problem(shape_id, true, "has no used solutions".to_string()),
+                        Some(used_solutions) => {
+                            for &solution_id in used_solutions {
+                                if solution_id >= parent_solutions {
+                                    problem(
+                                        shape_id,
+                                        true,
+                                        format!(
+                                            "uses solution {solution_id}, but parent {parent_id} has {parent_solutions}"
+                                        ),
+                                    );
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        match keys.entry((sorted(&shape.group), shape.parent)) {
+            std::collections::hash_map::Entry::Occupied(entry) => problem(
+                shape_id,
+                true,
+                format!("has the same group and parent as shape {}", entry.get()),
+            ),
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                entry.insert(shape_id);
+            }
+        }
+    }
+    problems
+}
+
+/// Describes what is wrong with the chain of parents above `shape_id`, if
+/// anything.
+fn broken_parent(shape_db: &[Shape], shape_id: ShapeId) -> Option<String> {
+    let mut current = shape_id;
+    for _ in 0..shape_db.len() {
+        let parent = shape_db[current].parent?;
+        if parent >= shape_db.len() {
+            return Some(format!("parent {parent} of shape {current} does not exist"));
+        }
+        current = parent;
+    }
+    Some("is its own ancestor".to_string())
+}
+
+fn sorted(points: &[(usize, usize)]) -> Vec<(usize, usize)> {
+    let mut points = points.to_vec();
+    points.sort_unstable();
+    points
+}
+
+/// Fixes the repairable problems [`check`] finds. Shapes with broken parents
+/// are left alone, and so are the solutions of shapes where some solution
+/// leaves the group. Returns what had to be given up along the way.
+fn repair_shape_db(shape_db: &mut ShapeDb) -> Vec<Problem> {
+    let broken = (0..shape_db.len())
+        .map(|shape_id| {
+            shape_db[shape_id].parent.is_some() && broken_parent(shape_db, shape_id).is_some()
+        })
+        .collect::<Vec<_>>();
+
+    // Drop solutions that repeat another one, remembering where every old
+    // solution went so that children can follow.
+    let mut solution_ids: Vec<Vec<usize>> = Vec::with_capacity(shape_db.len());
+    for shape in shape_db.iter_mut() {
+        let Some(solutions) = shape.solutions.take() else {
+            solution_ids.push(Vec::new());
+            continue;
+        };
+        if solutions
+            .iter()
+            .flatten()
+            .any(|point| !shape.group.contains(point))
+        {
+            solution_ids.push((0..solutions.len()).collect());
+            shape.solutions = Some(solutions);
+            continue;
+        }
+        let mut kept: Vec<Solution> = Vec::new();
+        let ids = solutions
+            .into_iter()
+            .map(|solution| {
+                let sorted_solution = sorted(&solution);
+                match kept
+                    .iter()
+                    .position(|known| sorted(known) == sorted_solution)
+                {
+                    Some(solution_id) => solution_id,
+                    None => {
+                        kept.push(solution);
+                        kept.len() - 1
+                    }
+                }
+            })
+            .collect();
+        shape.solutions = Some(kept);
+        solution_ids.push(ids);
+    }
+
+    for shape_id in 0..shape_db.len() {
+        let parent = shape_db[shape_id].parent;
+        let used_solutions = match parent {
+            None => None,
+            Some(_) if broken[shape_id] => shape_db[shape_id].used_solutions.clone(),
+            Some(parent) => {
+                let parent_solutions = shape_db[parent].solutions.as_ref().map_or(0, Vec::len);
+                let mut used_solutions = match &shape_db[shape_id].used_solutions {
+                    // Without a record of which solutions survived, all of
+                    // them might have.
+                    None => (0..parent_solutions).collect(),
+                    Some(used_solutions) => used_solutions
+                        .iter()
+                        .filter_map(|&solution_id| solution_ids[parent].get(solution_id).copied())
+                        .collect::<Vec<_>>(),
+                };
+                used_solutions.sort_unstable();
+                used_solutions.dedup();
+                Some(used_solutions)
+            }
+        };
+        shape_db[shape_id].used_solutions = used_solutions;
+    }
+
+    merge_duplicates(shape_db)
+}
+
+/// Folds shapes with the same group and parent into the first of them, and
+/// renumbers the rest. Children of a folded shape move to the one it was
+/// folded into, which can make them duplicates in turn. Used solutions that
+/// the folded parent never had are dropped and returned as problems.
+fn merge_duplicates(shape_db: &mut ShapeDb) -> Vec<Problem> {
+    let mut dropped = Vec::new();
+    loop {
+        let mut keys = HashMap::new();
+        let mut merged_into = vec![None; shape_db.len()];
+        for (shape_id, shape) in shape_db.iter().enumerate() {
+            let key = (sorted(&shape.group), shape.parent);
+            if let Some(&first) = keys.get(&key) {
+                merged_into[shape_id] = Some(first);
+            } else {
+                keys.insert(key, shape_id);
+            }
+        }
+        if merged_into.iter().all(Option::is_none) {
+            return dropped;
+        }
+
+        // Solutions of a folded shape move into the shape it is folded into.
+        let mut solution_ids: HashMap<ShapeId, Vec<usize>> = HashMap::new();
+        for shape_id in 0..shape_db.len() {
+            let Some(first) = merged_into[shape_id] else {
+                continue;
+            };
+            let solutions = shape_db[shape_id].solutions.clone().unwrap_or_default();
+            if let Some(used_solutions) = shape_db[shape_id].used_solutions.clone() {
+                let target = shape_db[first].used_solutions.get_or_insert_with(Vec::new);
+                target.extend(used_solutions);
+                target.sort_unstable();
+                target.dedup();
+            }
+            let target = shape_db[first].solutions.get_or_insert_with(Vec::new);
+            let ids = solutions
+                .into_iter()
+                .map(|solution| {
+                    let sorted_solution = sorted(&solution);
+                    target
+                        .iter()
+                        .position(|known| sorted(known) == sorted_solution)
+                        .unwrap_or_else(|| {
+                            target.push(solution);
+                            target.len() - 1
+                        })
+                })
+                .collect();
+            solution_ids.insert(shape_id, ids);
+        }
+
+        let mut new_ids = Vec::with_capacity(shape_db.len());
+        let mut next_id = 0;
+        for merged in &merged_into {
+            new_ids.push(next_id);
+            if merged.is_none() {
+                next_id += 1;
+            }
+        }
+        let new_id = |shape_id: ShapeId| new_ids[merged_into[shape_id].unwrap_or(shape_id)];
+
+        let old_db = std::mem::take(shape_db);
+        for (shape_id, mut shape) in old_db.into_iter().enumerate() {
+            if merged_into[shape_id].is_some() {
+                continue;
+            }
+            if let Some(parent) = shape.parent {
+                if let (Some(ids), Some(used_solutions)) =
+                    (solution_ids.get(&parent), &mut shape.used_solutions)
+                {
+                    used_solutions.retain_mut(|solution_id| match ids.get(*solution_id) {
+                        Some(&new_solution_id) => {
+                            *solution_id = new_solution_id;
+                            true
+                        }
+                        None => {
+                            dropped.push(Problem {
+                                shape_id,
+                                message: format!(
+                                    "dropped used solution {solution_id}, which parent {parent} does not have"
+                                ),
+                                repairable: false,
+                            });
+                            false
+                        }
+                    });
+                    used_solutions.sort_unstable();
+                    used_solutions.dedup();
+                }
+                if parent < new_ids.len() {
+                    shape.parent = Some(new_id(parent));
+                }
+            }
+            shape_db.push(shape);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Summary = (
+        Vec<(usize, usize)>,
+        Option<Vec<Solution>>,
+        Option<ShapeId>,
+        Option<Vec<usize>>,
+    );
+
+    fn shape(
+        group: &[(usize, usize)],
+        solutions: &[&[(usize, usize)]],
+        parent: Option<ShapeId>,
+        used_solutions: Option<&[usize]>,
+    ) -> Shape {
+        Shape {
+            group: group.to_vec(),
+            solutions: Some(solutions.iter().map(|solution| solution.to_vec()).collect()),
+            parent,
+            used_solutions: used_solutions.map(<[usize]>::to_vec),
+        }
+    }
+
+    fn summary(shape_db: &[Shape]) -> Vec<Summary> {
+        shape_db
+            .iter()
+            .map(|shape| {
+                (
+                    shape.group.clone(),
+                    shape.solutions.clone(),
+                    shape.parent,
+                    shape.used_solutions.clone(),
+                )
+            })
+            .collect()
+    }
+
+    fn domino() -> Vec<(usize, usize)> {
+        canonicalize_group(&[(0, 0), (0, 1)]).2
+    }
+
+    /// Every shape is found under its own key, which also means no key is
+    /// used twice.
+    fn assert_indexed(shape_db: &[Shape]) {
+        let index = tools::index_shape_db(shape_db);
+        assert_eq!(index.len(), shape_db.len());
+        for (shape_id, shape) in shape_db.iter().enumerate() {
+            assert_eq!(index[&(shape.group.clone(), shape.parent)], shape_id);
+        }
+    }
+
+    #[test]
+    fn dangling_parents_are_reported_and_left_alone() {
+        let d = domino();
+        let mut shape_db = vec![
+            shape(&d, &[&[], &[d[0]]], None, None),
+            shape(&d[..1], &[&[], &[d[0]]], Some(99), Some(&[0, 7])),
+        ];
+        let problems = check(&shape_db);
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].shape_id, 1);
+        assert!(!problems[0].repairable);
+
+        let before = summary(&shape_db);
+        assert!(repair_shape_db(&mut shape_db).is_empty());
+        assert_eq!(summary(&shape_db), before);
+    }
+
+    #[test]
+    fn duplicate_groups_are_merged_and_children_follow() {
+        let d = domino();
+        let mut shape_db = vec![
+            shape(&d, &[&[], &[d[0]]], None, None),
+            shape(&[(0, 0)], &[&[], &[(0, 0)]], None, None),
+            shape(&d, &[&[d[1]], &[d[0]]], None, None),
+            shape(&d[..1], &[&[]], Some(2), Some(&[0])),
+            shape(&[(0, 0)], &[&[]], Some(1), Some(&[0])),
+        ];
+        let problems = check(&shape_db);
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].shape_id, 2);
+        assert!(problems[0].repairable);
+
+        assert!(repair_shape_db(&mut shape_db).is_empty());
+        assert!(check(&shape_db).is_empty());
+        assert_indexed(&shape_db);
+        assert_eq!(
+            summary(&shape_db),
+            vec![
+                (
+                    d.clone(),
+                    Some(vec![vec![], vec![d[0]], vec![d[1]]]),
+                    None,
+                    None
+                ),
+                (vec![(0, 0)], Some(vec![vec![], vec![(0, 0)]]), None, None),
+                (d[..1].to_vec(), Some(vec![vec![]]), Some(0), Some(vec![2])),
+                (vec![(0, 0)], Some(vec![vec![]]), Some(1), Some(vec![0])),
+            ]
+        );
+    }
+
+    #[test]
+    fn out_of_range_used_solutions_are_trimmed() {
+        let d = domino();
+        let mut shape_db = vec![
+            shape(&d, &[&[], &[d[0]], &[d[0]]], None, None),
+            shape(&d[..1], &[&[]], Some(0), Some(&[2, 5])),
+        ];
+        let problems = check(&shape_db);
+        assert_eq!(problems.len(), 2);
+        assert!(problems.iter().all(|problem| problem.repairable));
+
+        assert!(repair_shape_db(&mut shape_db).is_empty());
+        assert!(check(&shape_db).is_empty());
+        assert_eq!(shape_db[0].solutions, Some(vec![vec![], vec![d[0]]]));
+        assert_eq!(shape_db[1].used_solutions, Some(vec![1]));
+    }
+
+    /// A child index entry under the parent's ID instead of the child's lets
+    /// the same child be added again, each copy with its own used solutions.
+    #[test]
+    fn children_added_twice_are_merged() {
+        let d = domino();
+        let mut shape_db = vec![
+            shape(&d, &[&[], &[d[0]], &[d[1]]], None, None),
+            shape(&d[..1], &[&[]], Some(0), Some(&[0])),
+            shape(&d[..1], &[&[d[0]]], Some(0), Some(&[2, 1])),
+        ];
+        assert!(repair_shape_db(&mut shape_db).is_empty());
+        assert!(check(&shape_db).is_empty());
+        assert_indexed(&shape_db);
+        assert_eq!(shape_db.len(), 2);
+        assert_eq!(shape_db[1].solutions, Some(vec![vec![], vec![d[0]]]));
+        assert_eq!(shape_db[1].used_solutions, Some(vec![0, 1, 2]));
+    }
+
+    #[test]
+    fn merging_reports_used_solutions_the_parent_never_had() {
+        let d = domino();
+        let mut shape_db = vec![
+            shape(&d, &[&[]], Some(99), None),
+            shape(&d, &[&[d[0]]], Some(99), None),
+            shape(&d[..1], &[&[]], Some(1), Some(&[0, 4])),
+        ];
+        let dropped = repair_shape_db(&mut shape_db);
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(dropped[0].shape_id, 2);
+        assert!(dropped[0].message.contains("used solution 4"));
+        assert_eq!(
+            summary(&shape_db),
+            vec![
+                (d.clone(), Some(vec![vec![], vec![d[0]]]), Some(99), None),
+                (d[..1].to_vec(), Some(vec![vec![]]), Some(0), Some(vec![1])),
+            ]
+        );
+    }
+}
@@ -1,17 +1,31 @@
+use std::process::ExitCode;
+
 use clap::Parser;
-use tools::{read_map, show_at};
+use tools::{Workspace, show_at};
 
 #[derive(clap::Parser)]
 struct Args {
     x: usize,
     y: usize,
+    #[clap(flatten)]
+    workspace: Workspace,
+}
+
+const SIZE: usize = 15;
+
+fn main() -> ExitCode {
+    tools::report(run())
 }
 
-fn main() {
+fn run() -> tools::Result<()> {
     let args = Args::parse();
     let x = args.x;
     let y = args.y;
 
-    let map = read_map();
-    show_at(&map, x, y, 15);
+    let mut map_file = args.workspace.open_map_file()?;
+    let x0 = x.saturating_sub(SIZE);
+    let y0 = y.saturating_sub(SIZE);
+    let window = map_file.read_window(x0, y0, x.saturating_add(SIZE), y.saturating_add(SIZE))?;
+    show_at(&window, x - x0, y - y0, SIZE);
+    Ok(())
 }
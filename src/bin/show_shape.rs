@@ -1,15 +1,23 @@
+use std::process::ExitCode;
+
 use clap::Parser;
-use tools::{read_shape_db, show_shape};
+use tools::{Error, Workspace, show_shape};
 
 #[derive(clap::Parser)]
 struct Args {
     shape_id: Option<usize>,
+    #[clap(flatten)]
+    workspace: Workspace,
+}
+
+pub fn main() -> ExitCode {
+    tools::report(run())
 }
 
-pub fn main() {
+fn run() -> tools::Result<()> {
     let args = Args::parse();
     let shape_id = args.shape_id;
-    let shape_db = read_shape_db();
+    let shape_db = args.workspace.read_shape_db()?;
 
     let shape_id = if let Some(shape_id) = shape_id {
         shape_id
@@ -17,19 +25,18 @@ pub fn main() {
         if let Some(id) = shape_db.iter().position(|shape| shape.solutions.is_none()) {
             id
         } else {
-            eprintln!("No shape without solutions found");
-            return;
+            println!("No shape without solutions found");
+            return Ok(());
         }
     };
     if shape_id >= shape_db.len() {
-        eprintln!(
-            "Shape ID {} out of bounds (max {})",
-            shape_id,
-            shape_db.len() - 1
-        );
-        return;
+        return Err(Error::InvalidArgument(format!(
+            "shape ID {shape_id} out of bounds ({} shapes)",
+            shape_db.len()
+        )));
     }
     let shape = &shape_db[shape_id];
     println!("Shape ID: {}", shape_id);
     show_shape(shape);
+    Ok(())
 }
@@ -1,10 +1,11 @@
-use std::collections::{HashMap, HashSet};
+use std::process::ExitCode;
 
 use clap::Parser;
 use tools::{
-    ACTIVE, CachedGroups, Map, NOT_ACTIVE, Shape, ShapeDb, ShapeDbIndex, ShapeId, Solution,
-    UNPROCESSED, find_group, normalize_group, read_cached_groups, read_shape_db,
-    write_cached_groups, write_map, write_shape_db,
+    Error, Grid, Workspace,
+    labels::Labels,
+    provenance::ProvenanceLog,
+    solver::{InconsistentError, Solver},
 };
 
 #[derive(clap::Parser)]
@@ -14,291 +15,113 @@ struct Args {
     step_x: usize,
     #[clap(long, default_value = "1")]
     step_y: usize,
+    /// Record why every tile was set in the provenance log, see `why`
+    #[clap(long)]
+    provenance: bool,
+    /// When propagation stalls, try every solution of the groups it reached
+    /// and keep the tiles the ones that do not lead to a contradiction agree on
+    #[clap(long)]
+    probe: bool,
+    #[clap(flatten)]
+    workspace: Workspace,
 }
 
-fn main() {
-    let args = Args::parse();
+fn main() -> ExitCode {
+    tools::report(run())
+}
 
-    let mut shape_db = read_shape_db();
-    let mut shape_db_index = shape_db
-        .iter()
-        .enumerate()
-        .map(|(shape_id, shape)| ((shape.group.clone(), shape.parent), shape_id))
-        .collect::<ShapeDbIndex>();
-    let mut cached_groups = read_cached_groups();
+fn run() -> tools::Result<()> {
+    let args = Args::parse();
 
-    let mut map = tools::read_map();
+    let workspace = args.workspace;
+    let shape_db = workspace.read_shape_db()?;
+    let map = workspace.read_map()?;
+    let cached_groups = workspace.read_cached_groups(&map, &shape_db)?;
     let mut todo: Vec<(usize, usize)> = Vec::new();
     for position in args.positions {
-        let (x, y) = position.split_once(",").unwrap();
-        let (x0, x1) = if let Some((x0, x1)) = x.split_once("..") {
-            (x0, x1)
-        } else {
-            (x, x)
-        };
-        let (y0, y1) = if let Some((y0, y1)) = y.split_once("..") {
-            (y0, y1)
-        } else {
-            (y, y)
+        let invalid = || {
+            Error::InvalidArgument(format!(
+                "invalid position {position:?}, expected X,Y where X and Y are numbers or ranges like 10..20"
+            ))
         };
-
-        let x0 = x0.parse().unwrap();
-        let x1 = x1.parse().unwrap();
-        let y0 = y0.parse().unwrap();
-        let y1 = y1.parse().unwrap();
+        let (x, y) = position.split_once(",").ok_or_else(invalid)?;
+        let (x0, x1) = parse_range(x).ok_or_else(invalid)?;
+        let (y0, y1) = parse_range(y).ok_or_else(invalid)?;
         for x in (x0..=x1).step_by(args.step_x) {
             for y in (y0..=y1).step_by(args.step_y) {
-                if x < tools::W && y < tools::H {
-                    todo.push((x, y));
-                }
+                todo.push((x, y));
             }
         }
     }
 
-    let mut solved_count = 0;
+    let mut provenance = args
+        .provenance
+        .then(|| ProvenanceLog::append(&workspace.provenance_log_path()))
+        .transpose()?;
     let shape_len_before = shape_db.len();
-
-    while let Some((x, y)) = todo.pop() {
-        if x >= tools::W || y >= tools::H {
-            continue;
-        }
-        if map[y][x] != UNPROCESSED {
-            continue; // Only process empty tiles
-        }
-
-        let ((min_x, min_y), shape_id) = get_group(&map, &shape_db_index, &mut cached_groups, x, y);
-        let shape = shape_db[shape_id].clone();
-
-        if let Some(unique_solution) = has_locally_unique_solution(
-            &map,
-            shape_id,
-            &shape,
-            &mut shape_db,
-            &mut shape_db_index,
-            &mut cached_groups,
-            min_x,
-            min_y,
-        )
-        .unwrap()
-        {
-            solved_count += 1;
-            if solved_count % 10000 == 0 {
-                println!("Solved {} tiles, todo.len == {}", solved_count, todo.len());
-                println!("Solving at ({}, {})", x, y);
-            }
-            for &((x, y), value) in &unique_solution {
-                let old_value = map[y][x];
-                map[y][x] = value;
-
-                if old_value != value {
-                    for (dx, dy) in &[(0, 1), (1, 0), (0, -1), (-1, 0)] {
-                        let nx = x.wrapping_add_signed(*dx);
-                        let ny = y.wrapping_add_signed(*dy);
-                        if let Some(potential_number) =
-                            map.get_mut(ny).and_then(|row| row.get_mut(nx))
-                        {
-                            if 1 <= *potential_number && *potential_number <= 3 {
-                                if value == ACTIVE {
-                                    assert!(*potential_number > 1);
-                                    *potential_number -= 1; // Decrease the neighbor tile count
-                                }
-                                for (dx, dy) in &[(0, 1), (1, 0), (0, -1), (-1, 0)] {
-                                    let nx = nx.wrapping_add_signed(*dx);
-                                    let ny = ny.wrapping_add_signed(*dy);
-                                    todo.push((nx, ny));
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-        }
+    let labels = Labels::compute(&map);
+    let mut solver = Solver::new(map, shape_db, cached_groups);
+    solver.use_labels(&labels);
+    if let Some(provenance) = &mut provenance {
+        solver.on_deduction(|event| provenance.record(event));
+    }
+    if args.probe {
+        solver.enable_probing();
+    }
+    solver.seed(todo);
+    if let Err(error) = propagate(&mut solver, args.probe) {
+        // The provenance log is dropped unfinished, since nothing is written.
+        let report = workspace.write_contradiction(solver.map(), &error)?;
+        return Err(Error::Inconsistent {
+            report,
+            source: error,
+        });
+    }
+    let stats = solver.stats();
+    println!("Solved {} tiles", stats.solved_groups);
+    println!(
+        "Deductions: {} memoized, {} computed",
+        stats.memo_hits, stats.memo_misses
+    );
+    if args.probe {
+        println!(
+            "Probed {} solutions, {} led to a contradiction",
+            stats.probes, stats.refuted_probes
+        );
     }
-    println!("Solved {} tiles", solved_count);
-    if shape_db.len() != shape_len_before {
+    let (map, shape_db, cached_groups) = solver.into_parts();
+    if stats.new_shapes > 0 {
         println!(
             "Shape database grew from {} to {} shapes",
             shape_len_before,
             shape_db.len()
         );
-        write_shape_db(&shape_db);
+        workspace.write_shape_db(&shape_db)?;
     }
-    write_cached_groups(&cached_groups);
-    write_map(&map);
-}
-
-fn get_group(
-    map: &Map,
-    shape_db_index: &ShapeDbIndex,
-    cached_groups: &mut CachedGroups,
-    x: usize,
-    y: usize,
-) -> ((usize, usize), ShapeId) {
-    if let Some(&group) = cached_groups.get(&(x, y)) {
-        group
-    } else {
-        let group = find_group(map, x, y);
-        let (min_x, min_y, normalized_group) = normalize_group(&group);
-        let key = (normalized_group, None);
-        let shape_id = *shape_db_index.get(&key).unwrap_or_else(|| {
-            panic!(
-                "Shape not found in index for group at ({}, {}): {:?}",
-                x, y, key.0
-            )
-        });
-        cached_groups.insert((x, y), ((min_x, min_y), shape_id));
-        ((min_x, min_y), shape_id)
+    workspace.write_cached_groups(&cached_groups, &map, &shape_db)?;
+    workspace.write_map(&map)?;
+    // Only now do the deductions in the log match the map on disk.
+    if let Some(provenance) = provenance {
+        provenance.finish()?;
     }
+    Ok(())
 }
 
-type Patch = ((usize, usize), u8);
-
-#[derive(Debug)]
-struct InconsistentError;
-
-impl std::fmt::Display for InconsistentError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Inconsistent state detected")
+/// Propagates, and with `probe` keeps probing until that commits nothing.
+fn propagate<G: Grid>(solver: &mut Solver<'_, G>, probe: bool) -> Result<(), InconsistentError> {
+    solver.propagate()?;
+    while probe && solver.probe()? > 0 {
+        solver.propagate()?;
     }
+    Ok(())
 }
 
-impl std::error::Error for InconsistentError {}
-
-fn has_locally_unique_solution(
-    map: &Map,
-    shape_id: ShapeId,
-    shape: &Shape,
-    shape_db: &mut ShapeDb,
-    shape_db_index: &mut ShapeDbIndex,
-    cached_groups: &mut CachedGroups,
-    min_x: usize,
-    min_y: usize,
-) -> Result<Option<Vec<Patch>>, InconsistentError> {
-    let mut found_patches: Option<Vec<((usize, usize), u8)>> = None;
-    let mut used_solutions = Vec::new();
-    let Some(solutions) = &shape.solutions else {
-        return Ok(None); // No solutions available for this shape
-    };
-    for (solution_id, solution) in solutions.iter().enumerate() {
-        if let Some(cur_patches) = find_solution_valid_at(map, shape, solution, min_x, min_y) {
-            if let Some(found_patches) = &mut found_patches {
-                found_patches.retain(|kv| cur_patches.contains(kv));
-            } else {
-                found_patches = Some(cur_patches.into_iter().collect());
-            }
-            used_solutions.push(solution_id);
-        }
-    }
-    let Some(found_patches) = found_patches else {
-        return Err(InconsistentError);
-    };
-    if found_patches.is_empty() {
-        Ok(None)
+/// Parses `N` or `N..M`, both inclusive.
+fn parse_range(range: &str) -> Option<(usize, usize)> {
+    if let Some((start, end)) = range.split_once("..") {
+        Some((start.parse().ok()?, end.parse().ok()?))
     } else {
-        let mut not_patched = shape
-            .group
-            .iter()
-            .filter_map(|&(x, y)| {
-                let actual_x = x + min_x;
-                let actual_y = y + min_y;
-                if found_patches
-                    .iter()
-                    .any(|(pos, _value)| *pos == (actual_x, actual_y))
-                {
-                    None
-                } else {
-                    Some((x, y))
-                }
-            })
-            .collect::<Vec<_>>();
-        if !not_patched.is_empty() {
-            not_patched.sort_unstable();
-            let key = (not_patched, Some(shape_id));
-            if let Some(shape_id) = shape_db_index.get(&key) {
-                for not_patched in key.0 {
-                    cached_groups.insert(
-                        (not_patched.0 + min_x, not_patched.1 + min_y),
-                        ((min_x, min_y), *shape_id),
-                    );
-                }
-            } else {
-                let child_shape_id = shape_db.len();
-                shape_db_index.insert((key.0.clone(), Some(shape_id)), shape_id);
-                shape_db.push(Shape {
-                    group: key.0.clone(),
-                    solutions: None, // Solutions can be added later
-                    parent: Some(shape_id),
-                    used_solutions: Some(used_solutions),
-                });
-                for not_patched in key.0 {
-                    cached_groups.insert(
-                        (not_patched.0 + min_x, not_patched.1 + min_y),
-                        ((min_x, min_y), child_shape_id),
-                    );
-                }
-            }
-        }
-        Ok(Some(found_patches.into_iter().collect()))
-    }
-}
-
-fn find_solution_valid_at(
-    map: &Map,
-    shape: &Shape,
-    solution: &Solution,
-    min_x: usize,
-    min_y: usize,
-) -> Option<Vec<Patch>> {
-    let mut neighbors: HashMap<(usize, usize), u8> = HashMap::new();
-    let mut us = HashSet::new();
-
-    let mut patches = Vec::new();
-
-    for &(x, y) in &shape.group {
-        let actual_x = x + min_x;
-        let actual_y = y + min_y;
-        us.insert((actual_x, actual_y));
-
-        for (dx, dy) in &[(0, 1), (1, 0), (0, -1), (-1, 0)] {
-            let nx = actual_x.wrapping_add_signed(*dx);
-            let ny = actual_y.wrapping_add_signed(*dy);
-            if let Some(&tile) = map.get(ny).and_then(|row| row.get(nx)) {
-                if 1 <= tile && tile <= 3 {
-                    let entry = neighbors.entry((nx, ny)).or_default();
-                    if solution.contains(&(x, y)) {
-                        *entry += 1;
-                    }
-                }
-            }
-        }
-        if solution.contains(&(x, y)) {
-            patches.push(((actual_x, actual_y), ACTIVE));
-        } else {
-            patches.push(((actual_x, actual_y), NOT_ACTIVE));
-        }
-    }
-
-    for (neighbor, &change) in &neighbors {
-        let tile = map[neighbor.1][neighbor.0];
-        if tile - change < 1 {
-            return None;
-        }
-        let other_tiles_needed = tile - 1 - change;
-        let mut other_tiles_available = 0;
-        for (dx, dy) in &[(0, 1), (1, 0), (0, -1), (-1, 0)] {
-            let nx = neighbor.0.wrapping_add_signed(*dx);
-            let ny = neighbor.1.wrapping_add_signed(*dy);
-            if !us.contains(&(nx, ny))
-                && map.get(ny).and_then(|row| row.get(nx)) == Some(&UNPROCESSED)
-            {
-                other_tiles_available += 1;
-            }
-        }
-
-        if other_tiles_available < other_tiles_needed {
-            return None;
-        }
+        let value = range.parse().ok()?;
+        Some((value, value))
     }
-
-    Some(patches)
 }
@@ -1,441 +1,277 @@
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::{
+    collections::{HashMap, HashSet},
+    process::ExitCode,
+};
 
 use clap::Parser;
 use tools::{
-    ACTIVE, CachedGroups, H, Map, NOT_ACTIVE, Shape, ShapeDb, ShapeDbIndex, ShapeId, Solution,
-    UNPROCESSED, W, normalize_group, read_cached_groups, read_shape_db, write_cached_groups_named,
-    write_map_named, write_shape_db,
+    DeductionMemo, Error, Grid, PackedMap, ShapeDb, ShapeDbIndex, Tile, Workspace, index_shape_db,
+    labels::Labels,
+    solver::{LabeledCache, TrailCache, TrailMap, commit_decided, get_group, try_solve},
+    write_cached_groups_named, write_map_named,
 };
 
 #[derive(clap::Parser)]
 struct Args {
     split_points: Vec<String>,
+    /// Instead of writing every surviving branch, commit only the tiles all
+    /// of them agree on
+    #[clap(long)]
+    consensus: bool,
+    #[clap(flatten)]
+    workspace: Workspace,
+}
+
+fn main() -> ExitCode {
+    tools::report(run())
 }
 
-fn main() {
+fn run() -> tools::Result<()> {
     let args = Args::parse();
 
-    let mut shape_db = read_shape_db();
-    let mut shape_db_index = shape_db
-        .iter()
-        .enumerate()
-        .map(|(shape_id, shape)| ((shape.group.clone(), shape.parent), shape_id))
-        .collect::<ShapeDbIndex>();
+    let workspace = args.workspace;
+    let mut shape_db = workspace.read_shape_db()?;
+    let mut shape_db_index = index_shape_db(&shape_db);
 
-    let map = tools::read_map();
-    let cached_groups = read_cached_groups();
+    let mut map = workspace.read_map()?;
+    let mut cached_groups = workspace.read_cached_groups(&map, &shape_db)?;
 
     let split_positions = args
         .split_points
         .iter()
-        .rev()
         .map(|s| {
-            let mut parts = s.split(',');
-            (
-                parts.next().unwrap().parse::<usize>().unwrap(),
-                parts.next().unwrap().parse::<usize>().unwrap(),
-            )
+            s.split_once(',')
+                .and_then(|(x, y)| Some((x.parse::<usize>().ok()?, y.parse::<usize>().ok()?)))
+                .ok_or_else(|| {
+                    Error::InvalidArgument(format!("invalid split point {s:?}, expected X,Y"))
+                })
         })
-        .collect::<Vec<_>>();
+        .collect::<tools::Result<Vec<_>>>()?;
+
+    let labels = Labels::compute(&map);
+    let mut trail_map = TrailMap::new(&mut map);
+    let mut trail_cache = TrailCache::new(&mut cached_groups);
+    let mut memo = DeductionMemo::default();
+    if !args.consensus {
+        let mut count = 0;
+        depth_first_solver(
+            &mut trail_map,
+            &mut trail_cache,
+            &split_positions,
+            &mut shape_db,
+            &mut shape_db_index,
+            &labels,
+            &mut memo,
+            &mut |map, cached_groups, shape_db| {
+                write_map_named(map.map(), &workspace.path(format!("solution_{count}.raw")))?;
+                write_cached_groups_named(
+                    cached_groups.cached_groups(),
+                    map.map(),
+                    shape_db,
+                    &workspace.path(format!("cached_groups_{count}.bin")),
+                )?;
+                count += 1;
+                Ok(())
+            },
+        )?;
+        return workspace.write_shape_db(&shape_db);
+    }
 
-    breadth_first_solver(
-        map,
-        cached_groups,
-        split_positions,
+    let mut consensus = Consensus::default();
+    depth_first_solver(
+        &mut trail_map,
+        &mut trail_cache,
+        &split_positions,
         &mut shape_db,
         &mut shape_db_index,
+        &labels,
+        &mut memo,
+        &mut |map, _, _| {
+            consensus.add(map.changes());
+            Ok(())
+        },
+    )?;
+
+    if consensus.branches == 0 {
+        println!("No branch survived, nothing to commit");
+        return workspace.write_shape_db(&shape_db);
+    }
+    // Every branch has been undone, so `map` is back to where it started.
+    let unprocessed = |&(x, y): &(usize, usize)| map.get(x, y) == Tile::Unprocessed;
+    let decided = consensus
+        .agreed
+        .into_iter()
+        .filter(|(position, tile)| {
+            unprocessed(position) && matches!(tile, Tile::Active | Tile::NotActive)
+        })
+        .collect::<HashMap<_, _>>();
+    let mut differ = consensus
+        .differ
+        .into_iter()
+        .filter(unprocessed)
+        .collect::<Vec<_>>();
+    differ.sort_unstable();
+    println!(
+        "{} branches survived, they agree on {} tiles and differ on {}",
+        consensus.branches,
+        decided.len(),
+        differ.len()
     );
-    write_shape_db(&shape_db);
-}
-
-#[derive(Clone)]
-struct MapWithPatches<'a> {
-    map: &'a Map,
-    patches: HashMap<(usize, usize), u8>,
-}
+    for (x, y) in differ.iter().take(MAX_LISTED_DIFFERENCES) {
+        println!("  ({x}, {y})");
+    }
+    if differ.len() > MAX_LISTED_DIFFERENCES {
+        println!("  and {} more", differ.len() - MAX_LISTED_DIFFERENCES);
+    }
 
-impl MapWithPatches<'_> {
-    fn get(&self, x: usize, y: usize) -> u8 {
-        if let Some(&value) = self.patches.get(&(x, y)) {
-            value
-        } else {
-            self.map[y][x]
+    let committed = match commit_decided(
+        &mut map,
+        &decided,
+        &mut shape_db,
+        &mut shape_db_index,
+        &mut LabeledCache::new(&mut cached_groups, Some(&labels)),
+    ) {
+        Ok(committed) => committed,
+        Err(error) => {
+            // The branches each fit, so this means the deductions are wrong.
+            println!("The tiles every branch agrees on do not fit together");
+            return Err(Error::Inconsistent {
+                report: workspace.write_contradiction(&map, &error)?,
+                source: error,
+            });
         }
+    };
+    println!("Committed tiles in {committed} groups");
+    workspace.write_shape_db(&shape_db)?;
+    if committed > 0 {
+        workspace.write_cached_groups(&cached_groups, &map, &shape_db)?;
+        workspace.write_map(&map)?;
     }
+    Ok(())
+}
 
-    fn set(&mut self, x: usize, y: usize, value: u8) {
-        self.patches.insert((x, y), value);
-    }
+/// How many of the tiles the branches differ on are listed.
+const MAX_LISTED_DIFFERENCES: usize = 20;
+
+/// The tiles every surviving branch changed the same way.
+#[derive(Default)]
+struct Consensus {
+    branches: usize,
+    agreed: HashMap<(usize, usize), Tile>,
+    /// Tiles that some branches changed and others did not, or changed
+    /// differently.
+    differ: HashSet<(usize, usize)>,
+}
 
-    fn apply(&self, map: &mut Map) {
-        for (&(x, y), &value) in &self.patches {
-            map[y][x] = value;
+impl Consensus {
+    fn add(&mut self, changes: HashMap<(usize, usize), Tile>) {
+        if self.branches == 0 {
+            self.agreed = changes;
+        } else {
+            for (position, tile) in &changes {
+                if self.agreed.get(position) != Some(tile) {
+                    self.differ.insert(*position);
+                }
+            }
+            self.agreed.retain(|position, tile| {
+                let same = changes.get(position) == Some(tile);
+                if !same {
+                    self.differ.insert(*position);
+                }
+                same
+            });
         }
+        self.branches += 1;
     }
 }
 
-fn breadth_first_solver(
-    real_map: Box<Map>,
-    initial_cached_groups: CachedGroups,
-    initial_positions: Vec<(usize, usize)>,
+/// Tries every solution of the group at each position in turn, and calls
+/// `on_solution` for every combination that does not lead to a
+/// contradiction. Branches are explored depth first on a single map, and
+/// undone when they are done.
+#[allow(clippy::too_many_arguments)]
+fn depth_first_solver(
+    map: &mut TrailMap<'_, PackedMap>,
+    cached_groups: &mut TrailCache<'_>,
+    positions: &[(usize, usize)],
     shape_db: &mut ShapeDb,
-    shape_db_index: &mut HashMap<(Vec<(usize, usize)>, Option<usize>), usize>,
-) {
-    let initial_map = MapWithPatches {
-        map: &real_map,
-        patches: Default::default(),
+    shape_db_index: &mut ShapeDbIndex,
+    labels: &Labels,
+    memo: &mut DeductionMemo,
+    on_solution: &mut impl FnMut(
+        &TrailMap<'_, PackedMap>,
+        &TrailCache<'_>,
+        &ShapeDb,
+    ) -> tools::Result<()>,
+) -> tools::Result<()> {
+    let Some((&(x, y), positions)) = positions.split_first() else {
+        return on_solution(map, cached_groups, shape_db);
     };
-    let mut todo: VecDeque<(MapWithPatches, CachedGroups, Vec<(usize, usize)>)> = VecDeque::new();
-    todo.push_back((initial_map, initial_cached_groups, initial_positions));
-
-    let mut count = 0;
-
-    while let Some((map, mut cached_groups, mut positions)) = todo.pop_front() {
-        let Some((x, y)) = positions.pop() else {
-            let mut real_map = real_map.clone();
-            map.apply(&mut real_map);
-            write_map_named(&real_map, &format!("solution_{count}.raw"));
-            write_cached_groups_named(&cached_groups, &format!("cached_groups_{count}.bin"));
-            count += 1;
+    println!(
+        "Processing position ({x}, {y}), remaining positions {}",
+        positions.len()
+    );
 
-            continue;
-        };
-        println!(
-            "Processing position ({}, {}), remaining postion {}, in queue {}",
-            x,
-            y,
-            positions.len(),
-            todo.len()
+    if map.get(x, y) != Tile::Unprocessed {
+        return depth_first_solver(
+            map,
+            cached_groups,
+            positions,
+            shape_db,
+            shape_db_index,
+            labels,
+            memo,
+            on_solution,
         );
-
-        if map.get(x, y) != UNPROCESSED {
-            todo.push_back((map, cached_groups, positions));
-            continue;
-        }
-        let ((min_x, min_y), shape_id) = get_group(&map, &shape_db_index, &mut cached_groups, x, y);
-        let shape = shape_db[shape_id].clone();
-
-        let solutions = shape.solutions.clone().unwrap();
-
-        for solution in &solutions {
-            let mut map = map.clone();
-            let mut cached_groups = cached_groups.clone();
-            if try_solve(
-                shape_db,
-                shape_db_index,
-                &mut map,
-                min_x,
-                min_y,
-                shape_id,
-                &Shape {
-                    solutions: Some(vec![solution.clone()]),
-                    ..shape.clone()
-                },
-                &mut cached_groups,
-            )
-            .is_ok()
-            {
-                todo.push_back((map, cached_groups, positions.clone()));
-            }
-        }
     }
-}
-
-fn try_solve(
-    shape_db: &mut Vec<Shape>,
-    shape_db_index: &mut HashMap<(Vec<(usize, usize)>, Option<usize>), usize>,
-    map: &mut MapWithPatches<'_>,
-    min_x: usize,
-    min_y: usize,
-    shape_id: usize,
-    shape: &Shape,
-    cached_groups: &mut HashMap<(usize, usize), ((usize, usize), usize)>,
-) -> Result<(), InconsistentError> {
-    let mut todo = vec![];
-    if let Some(unique_solution) = has_locally_unique_solution(
+    let (anchor, shape_id, orientation) = get_group(
         &*map,
-        shape_id,
-        &shape,
-        shape_db,
         shape_db_index,
-        cached_groups,
-        min_x,
-        min_y,
-    )? {
-        for &((x, y), value) in &unique_solution {
-            let old_value = map.get(x, y);
-            map.set(x, y, value);
-
-            if old_value != value {
-                for (dx, dy) in &[(0, 1), (1, 0), (0, -1), (-1, 0)] {
-                    let nx = x.wrapping_add_signed(*dx);
-                    let ny = y.wrapping_add_signed(*dy);
-                    if nx < W && ny < H {
-                        let potential_number = map.get(nx, ny);
-                        if 1 <= potential_number && potential_number <= 3 {
-                            if value == ACTIVE {
-                                assert!(potential_number > 1);
-                                map.set(nx, ny, potential_number - 1); // Decrease the neighbor tile count
-                            }
-                            for (dx, dy) in &[(0, 1), (1, 0), (0, -1), (-1, 0)] {
-                                let nx = nx.wrapping_add_signed(*dx);
-                                let ny = ny.wrapping_add_signed(*dy);
-                                todo.push((nx, ny));
-                            }
-                        }
-                    }
-                }
-            }
-        }
-    }
-
-    while let Some((x, y)) = todo.pop() {
-        if x >= tools::W || y >= tools::H {
-            continue;
-        }
-        if map.get(x, y) != UNPROCESSED {
-            continue; // Only process empty tiles
-        }
+        &mut LabeledCache::new(&mut *cached_groups, Some(labels)),
+        x,
+        y,
+    );
+    let shape = shape_db[shape_id].clone();
 
-        let ((min_x, min_y), shape_id) = get_group(&*map, &*shape_db_index, cached_groups, x, y);
-        let shape = shape_db[shape_id].clone();
+    let Some(solution_count) = shape.solutions.as_ref().map(Vec::len) else {
+        return Err(Error::InvalidArgument(format!(
+            "shape {shape_id} at ({x}, {y}) has no solutions yet, add them with insert_shape"
+        )));
+    };
 
-        if let Some(unique_solution) = has_locally_unique_solution(
-            &*map,
+    let map_mark = map.mark();
+    let cache_mark = cached_groups.mark();
+    for solution_id in 0..solution_count {
+        match try_solve(
+            map,
             shape_id,
             &shape,
+            &[solution_id],
             shape_db,
             shape_db_index,
-            cached_groups,
-            min_x,
-            min_y,
-        )? {
-            for &((x, y), value) in &unique_solution {
-                let old_value = map.get(x, y);
-                map.set(x, y, value);
-
-                if old_value != value {
-                    for (dx, dy) in &[(0, 1), (1, 0), (0, -1), (-1, 0)] {
-                        let nx = x.wrapping_add_signed(*dx);
-                        let ny = y.wrapping_add_signed(*dy);
-                        if nx < W && ny < H {
-                            let potential_number = map.get(nx, ny);
-                            if 1 <= potential_number && potential_number <= 3 {
-                                if value == ACTIVE {
-                                    assert!(potential_number > 1);
-                                    map.set(nx, ny, potential_number - 1); // Decrease the neighbor tile count
-                                }
-                                for (dx, dy) in &[(0, 1), (1, 0), (0, -1), (-1, 0)] {
-                                    let nx = nx.wrapping_add_signed(*dx);
-                                    let ny = ny.wrapping_add_signed(*dy);
-                                    todo.push((nx, ny));
-                                }
-                            }
-                        }
-                    }
-                }
+            &mut LabeledCache::new(&mut *cached_groups, Some(labels)),
+            anchor,
+            orientation,
+            memo,
+        ) {
+            Ok(()) => depth_first_solver(
+                map,
+                cached_groups,
+                positions,
+                shape_db,
+                shape_db_index,
+                labels,
+                memo,
+                on_solution,
+            )?,
+            Err(error) => {
+                println!(
+                    "Dropping solution {solution_id} of shape {shape_id} at ({x}, {y}): {error}"
+                )
             }
         }
+        map.undo_to(map_mark);
+        cached_groups.undo_to(cache_mark, shape_db, memo);
     }
-
     Ok(())
 }
-
-fn find_group(map: &MapWithPatches<'_>, x: usize, y: usize) -> Vec<(usize, usize)> {
-    let mut group = Vec::new();
-    let mut stack = vec![(x, y)];
-    let tile = map.get(x, y);
-    assert_eq!(tile, 5, "Expected tile at ({}, {}) to be 5", x, y);
-
-    while let Some((cx, cy)) = stack.pop() {
-        for (nx, ny) in [
-            (cx.wrapping_sub(1), cy), // left
-            (cx + 1, cy),             // right
-            (cx, cy.wrapping_sub(1)), // up
-            (cx, cy + 1),             // down
-        ] {
-            if nx < W && ny < H && map.get(nx, ny) == 5 && !group.contains(&(nx, ny)) {
-                group.push((nx, ny));
-                stack.push((nx, ny));
-            }
-        }
-    }
-
-    group
-}
-
-fn get_group(
-    map: &MapWithPatches,
-    shape_db_index: &ShapeDbIndex,
-    cached_groups: &mut CachedGroups,
-    x: usize,
-    y: usize,
-) -> ((usize, usize), ShapeId) {
-    if let Some(&group) = cached_groups.get(&(x, y)) {
-        group
-    } else {
-        let group = find_group(map, x, y);
-        let (min_x, min_y, normalized_group) = normalize_group(&group);
-        let key = (normalized_group, None);
-        let shape_id = *shape_db_index.get(&key).unwrap_or_else(|| {
-            panic!(
-                "Shape not found in index for group at ({}, {}): {:?}",
-                x, y, key.0
-            )
-        });
-        cached_groups.insert((x, y), ((min_x, min_y), shape_id));
-        ((min_x, min_y), shape_id)
-    }
-}
-
-type Patch = ((usize, usize), u8);
-
-#[derive(Debug)]
-struct InconsistentError;
-
-impl std::fmt::Display for InconsistentError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Inconsistent state detected")
-    }
-}
-
-impl std::error::Error for InconsistentError {}
-
-fn has_locally_unique_solution(
-    map: &MapWithPatches<'_>,
-    shape_id: ShapeId,
-    shape: &Shape,
-    shape_db: &mut ShapeDb,
-    shape_db_index: &mut ShapeDbIndex,
-    cached_groups: &mut CachedGroups,
-    min_x: usize,
-    min_y: usize,
-) -> Result<Option<Vec<Patch>>, InconsistentError> {
-    let mut found_patches: Option<Vec<((usize, usize), u8)>> = None;
-    let mut used_solutions = Vec::new();
-    let Some(solutions) = &shape.solutions else {
-        return Ok(None); // No solutions available for this shape
-    };
-    for (solution_id, solution) in solutions.iter().enumerate() {
-        if let Some(cur_patches) = find_solution_valid_at(map, shape, solution, min_x, min_y) {
-            if let Some(found_patches) = &mut found_patches {
-                found_patches.retain(|kv| cur_patches.contains(kv));
-            } else {
-                found_patches = Some(cur_patches.into_iter().collect());
-            }
-            used_solutions.push(solution_id);
-        }
-    }
-    let Some(found_patches) = found_patches else {
-        return Err(InconsistentError);
-    };
-    if found_patches.is_empty() {
-        Ok(None)
-    } else {
-        let mut not_patched = shape
-            .group
-            .iter()
-            .filter_map(|&(x, y)| {
-                let actual_x = x + min_x;
-                let actual_y = y + min_y;
-                if found_patches
-                    .iter()
-                    .any(|(pos, _value)| *pos == (actual_x, actual_y))
-                {
-                    None
-                } else {
-                    Some((x, y))
-                }
-            })
-            .collect::<Vec<_>>();
-        if !not_patched.is_empty() {
-            not_patched.sort_unstable();
-            let key = (not_patched, Some(shape_id));
-            if let Some(shape_id) = shape_db_index.get(&key) {
-                for not_patched in key.0 {
-                    cached_groups.insert(
-                        (not_patched.0 + min_x, not_patched.1 + min_y),
-                        ((min_x, min_y), *shape_id),
-                    );
-                }
-            } else {
-                let child_shape_id = shape_db.len();
-                shape_db_index.insert((key.0.clone(), Some(shape_id)), shape_id);
-                shape_db.push(Shape {
-                    group: key.0.clone(),
-                    solutions: None, // Solutions can be added later
-                    parent: Some(shape_id),
-                    used_solutions: Some(used_solutions),
-                });
-                for not_patched in key.0 {
-                    cached_groups.insert(
-                        (not_patched.0 + min_x, not_patched.1 + min_y),
-                        ((min_x, min_y), child_shape_id),
-                    );
-                }
-            }
-        }
-        Ok(Some(found_patches.into_iter().collect()))
-    }
-}
-
-fn find_solution_valid_at(
-    map: &MapWithPatches<'_>,
-    shape: &Shape,
-    solution: &Solution,
-    min_x: usize,
-    min_y: usize,
-) -> Option<Vec<Patch>> {
-    let mut neighbors: HashMap<(usize, usize), u8> = HashMap::new();
-    let mut us = HashSet::new();
-
-    let mut patches = Vec::new();
-
-    for &(x, y) in &shape.group {
-        let actual_x = x + min_x;
-        let actual_y = y + min_y;
-        us.insert((actual_x, actual_y));
-
-        for (dx, dy) in &[(0, 1), (1, 0), (0, -1), (-1, 0)] {
-            let nx = actual_x.wrapping_add_signed(*dx);
-            let ny = actual_y.wrapping_add_signed(*dy);
-            if nx >= W || ny >= H {
-                continue; // Skip out-of-bounds neighbors
-            }
-            let tile = map.get(nx, ny);
-            if 1 <= tile && tile <= 3 {
-                let entry = neighbors.entry((nx, ny)).or_default();
-                if solution.contains(&(x, y)) {
-                    *entry += 1;
-                }
-            }
-        }
-        if solution.contains(&(x, y)) {
-            patches.push(((actual_x, actual_y), ACTIVE));
-        } else {
-            patches.push(((actual_x, actual_y), NOT_ACTIVE));
-        }
-    }
-
-    for (neighbor, &change) in &neighbors {
-        let tile = map.get(neighbor.0, neighbor.1);
-        if tile - change < 1 {
-            return None;
-        }
-        let other_tiles_needed = tile - 1 - change;
-        let mut other_tiles_available = 0;
-        for (dx, dy) in &[(0, 1), (1, 0), (0, -1), (-1, 0)] {
-            let nx = neighbor.0.wrapping_add_signed(*dx);
-            let ny = neighbor.1.wrapping_add_signed(*dy);
-            if !us.contains(&(nx, ny)) && map.get(nx, ny) == UNPROCESSED {
-                other_tiles_available += 1;
-            }
-        }
-
-        if other_tiles_available < other_tiles_needed {
-            return None;
-        }
-    }
-
-    Some(patches)
-}
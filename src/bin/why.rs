@@ -0,0 +1,96 @@
+use std::process::ExitCode;
+
+use clap::Parser;
+use tools::{
+    Error, Workspace,
+    provenance::{Deduction, Run, deductions_at, read_provenance},
+};
+
+#[derive(clap::Parser)]
+struct Args {
+    /// The tile to explain, as X,Y
+    position: String,
+    /// List every recorded deduction that set the tile, not only the last
+    #[clap(long)]
+    all: bool,
+    #[clap(flatten)]
+    workspace: Workspace,
+}
+
+fn main() -> ExitCode {
+    tools::report(run())
+}
+
+fn run() -> tools::Result<()> {
+    let args = Args::parse();
+    let invalid = || {
+        Error::InvalidArgument(format!(
+            "invalid position {:?}, expected X,Y",
+            args.position
+        ))
+    };
+    let (x, y) = args.position.split_once(",").ok_or_else(invalid)?;
+    let position: (usize, usize) = (
+        x.trim().parse().map_err(|_| invalid())?,
+        y.trim().parse().map_err(|_| invalid())?,
+    );
+
+    let path = args.workspace.provenance_log_path();
+    let runs = read_provenance(&path)?;
+    if runs.is_empty() {
+        println!(
+            "Nothing recorded in {}, run solve with --provenance",
+            path.display()
+        );
+        return Ok(());
+    }
+
+    let mut found = deductions_at(&runs, position);
+    let Some((run, deduction)) = found.next() else {
+        let aborted = runs.iter().filter(|run| !run.finished).count();
+        println!(
+            "No recorded deduction set {position:?} in {} runs ({aborted} of them aborted and skipped)",
+            runs.len()
+        );
+        return Ok(());
+    };
+    explain(position, run, deduction);
+    if args.all {
+        for (run, deduction) in found {
+            println!();
+            explain(position, run, deduction);
+        }
+    }
+    Ok(())
+}
+
+/// Prints what `deduction` set `position` to, and the chain of deductions
+/// back to the seed that led to it.
+fn explain(position: (usize, usize), run: &Run, deduction: &Deduction) {
+    let &(_, old, new) = deduction
+        .patches
+        .iter()
+        .find(|&&(patched, _, _)| patched == position)
+        .unwrap();
+    println!(
+        "{position:?} was set from {old:?} to {new:?} by `{}` (started at {})",
+        run.meta.command_line.join(" "),
+        run.meta.created
+    );
+    let mut current = deduction;
+    loop {
+        println!(
+            "  deduction {}: shape {} at {:?}, solutions {:?} fit, set {} tiles",
+            current.id,
+            current.shape_id,
+            current.origin,
+            current.used_solutions,
+            current.patches.len()
+        );
+        let Some(cause) = current.cause else {
+            break;
+        };
+        current = &run.deductions[cause];
+    }
+    println!("  seeded at {:?}", deduction.seed);
+}
@@ -0,0 +1,363 @@
+//! Checkpoints bundle the map, the cached groups and the shape database into
+//! a single compressed file, so a restored map can never be paired with a
+//! cache or shape database from a different run.
+
+use std::{
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+
+use crate::{
+    Error, PackedMap, Result, Workspace, content_hash, encode_header, exists, is_dat,
+    raw_header_path, read_dat_from, read_or_truncated, write_dat_to, write_raw_to,
+};
+
+/// Checkpoints live in this directory inside the workspace.
+pub const CHECKPOINT_DIR: &str = "checkpoints";
+
+const MAGIC: &[u8; 8] = b"PPCKPT01";
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct CheckpointMeta {
+    /// Seconds since the Unix epoch.
+    pub created: u64,
+    pub command_line: Vec<String>,
+    pub width: usize,
+    pub height: usize,
+    pub map_hash: u64,
+    pub shape_db_hash: u64,
+    /// `None` if there was no `cached_groups.bin` to save.
+    pub cached_groups_hash: Option<u64>,
+}
+
+pub struct Checkpoint {
+    pub meta: CheckpointMeta,
+    pub map: PackedMap,
+    /// The contents of `shape_db.json`.
+    pub shape_db: Vec<u8>,
+    /// The contents of `cached_groups.bin`, if it exists.
+    pub cached_groups: Option<Vec<u8>>,
+}
+
+pub fn checkpoint_path(workspace: &Workspace, name: &str) -> PathBuf {
+    workspace.path(CHECKPOINT_DIR).join(format!("{name}.ckpt"))
+}
+
+impl Checkpoint {
+    /// Captures the current map, shape database and cached groups. The files
+    /// are stored byte for byte, so restoring gives back exactly what was
+    /// captured.
+    pub fn capture(workspace: &Workspace) -> Result<Self> {
+        let map = workspace.read_map()?;
+        let shape_db_path = workspace.shape_db_path();
+        let shape_db = std::fs::read(&shape_db_path).map_err(Error::read(&shape_db_path))?;
+        let cache_path = workspace.cache_path();
+        let cached_groups = if exists(&cache_path)? {
+            Some(std::fs::read(&cache_path).map_err(Error::read(&cache_path))?)
+        } else {
+            None
+        };
+
+        let meta = CheckpointMeta {
+            created: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            command_line: std::env::args().collect(),
+            width: map.width(),
+            height: map.height(),
+            map_hash: map.fingerprint(),
+            shape_db_hash: content_hash(&shape_db),
+            cached_groups_hash: cached_groups.as_deref().map(content_hash),
+        };
+        Ok(Checkpoint {
+            meta,
+            map,
+            shape_db,
+            cached_groups,
+        })
+    }
+
+    /// Writes the checkpointed files back to the workspace. Every file is
+    /// first written next to the one it replaces, and only renamed over it
+    /// once all of them are written, so a failed restore leaves the workspace
+    /// as it was.
+    pub fn restore(&self, workspace: &Workspace) -> Result<()> {
+        let mut staged = Vec::new();
+        if let Err(error) = self.stage(workspace, &mut staged) {
+            for (staging_path, _) in &staged {
+                let _ = std::fs::remove_file(staging_path);
+            }
+            return Err(error);
+        }
+        for (staging_path, path) in &staged {
+            std::fs::rename(staging_path, path).map_err(Error::write(path))?;
+            println!("Written to {}", path.display());
+        }
+        let cache_path = workspace.cache_path();
+        if self.cached_groups.is_none() && exists(&cache_path)? {
+            std::fs::remove_file(&cache_path).map_err(Error::write(&cache_path))?;
+            println!("Removed {}", cache_path.display());
+        }
+        Ok(())
+    }
+
+    /// Writes the files [`Checkpoint::restore`] renames into place, and adds
+    /// each one to `staged` along with where it goes.
+    fn stage(&self, workspace: &Workspace, staged: &mut Vec<(PathBuf, PathBuf)>) -> Result<()> {
+        let map_path = workspace.map_path();
+        if is_dat(&map_path) {
+            stage_file(&map_path, staged, |writer| write_dat_to(&self.map, writer))?;
+        } else {
+            stage_file(&map_path, staged, |writer| write_raw_to(&self.map, writer))?;
+            stage_file(&raw_header_path(&map_path), staged, |writer| {
+                writer.write_all(&encode_header(self.map.width(), self.map.height()))
+            })?;
+        }
+        stage_file(&workspace.shape_db_path(), staged, |writer| {
+            writer.write_all(&self.shape_db)
+        })?;
+        if let Some(cached_groups) = &self.cached_groups {
+            stage_file(&workspace.cache_path(), staged, |writer| {
+                writer.write_all(cached_groups)
+            })?;
+        }
+        Ok(())
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let file = std::fs::File::create(path).map_err(Error::write(path))?;
+        let mut writer = GzEncoder::new(std::io::BufWriter::new(file), Compression::fast());
+        self.write_to(&mut writer)
+            .and_then(|_| writer.finish())
+            .and_then(|mut writer| writer.flush())
+            .map_err(Error::write(path))?;
+        println!("Written checkpoint to {}", path.display());
+        Ok(())
+    }
+
+    fn write_to(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        writer.write_all(MAGIC)?;
+        write_section(
+            writer,
+            &serde_cbor::to_vec(&self.meta).expect("Failed to serialize checkpoint metadata"),
+        )?;
+        write_section(writer, &self.shape_db)?;
+        if let Some(cached_groups) = &self.cached_groups {
+            writer.write_all(&[1])?;
+            write_section(writer, cached_groups)?;
+        } else {
+            writer.write_all(&[0])?;
+        }
+        write_dat_to(&self.map, writer)
+    }
+
+    /// Reads a checkpoint and checks every part against its stored hash.
+    pub fn read(path: &Path) -> Result<Self> {
+        let mut reader = open(path)?;
+        let meta = read_meta_from(&mut reader, path)?;
+        let shape_db = read_section(&mut reader, path)?;
+        let mut has_cached_groups = [0];
+        read_or_truncated(&mut reader, &mut has_cached_groups, path)?;
+        let cached_groups = if has_cached_groups[0] != 0 {
+            Some(read_section(&mut reader, path)?)
+        } else {
+            None
+        };
+        let map = read_dat_from(&mut reader, path)?;
+
+        if map.fingerprint() != meta.map_hash {
+            return Err(Error::corrupt(path, "map hash mismatch"));
+        }
+        if content_hash(&shape_db) != meta.shape_db_hash {
+            return Err(Error::corrupt(path, "shape database hash mismatch"));
+        }
+        if cached_groups.as_deref().map(content_hash) != meta.cached_groups_hash {
+            return Err(Error::corrupt(path, "cached groups hash mismatch"));
+        }
+        Ok(Checkpoint {
+            meta,
+            map,
+            shape_db,
+            cached_groups,
+        })
+    }
+
+    /// Reads only the metadata, without decompressing the map.
+    pub fn read_meta(path: &Path) -> Result<CheckpointMeta> {
+        read_meta_from(&mut open(path)?, path)
+    }
+}
+
+/// Lists the checkpoints in the workspace, oldest first.
+pub fn list_checkpoints(workspace: &Workspace) -> Result<Vec<(String, CheckpointMeta)>> {
+    let dir = workspace.path(CHECKPOINT_DIR);
+    if !exists(&dir)? {
+        return Ok(Vec::new());
+    }
+    let mut checkpoints = Vec::new();
+    for entry in std::fs::read_dir(&dir).map_err(Error::read(&dir))? {
+        let path = entry.map_err(Error::read(&dir))?.path();
+        let Some(name) = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(".ckpt"))
+        else {
+            continue;
+        };
+        checkpoints.push((name.to_string(), Checkpoint::read_meta(&path)?));
+    }
+    checkpoints.sort_by_key(|(name, meta)| (meta.created, name.clone()));
+    Ok(checkpoints)
+}
+
+fn open(path: &Path) -> Result<GzDecoder<std::io::BufReader<std::fs::File>>> {
+    let file = std::fs::File::open(path).map_err(Error::read(path))?;
+    let mut reader = GzDecoder::new(std::io::BufReader::new(file));
+    let mut magic = [0; MAGIC.len()];
+    read_or_truncated(&mut reader, &mut magic, path)?;
+    if &magic != MAGIC {
+        return Err(Error::corrupt(path, "not a checkpoint"));
+    }
+    Ok(reader)
+}
+
+fn read_meta_from(reader: &mut impl Read, path: &Path) -> Result<CheckpointMeta> {
+    serde_cbor::from_slice(&read_section(reader, path)?).map_err(|source| Error::Cbor {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+/// Writes the file that is to replace `path` next to it.
+fn stage_file(
+    path: &Path,
+    staged: &mut Vec<(PathBuf, PathBuf)>,
+    write: impl FnOnce(&mut std::io::BufWriter<std::fs::File>) -> std::io::Result<()>,
+) -> Result<()> {
+    let mut staging_path = path.as_os_str().to_owned();
+    staging_path.push(".restoring");
+    let staging_path = PathBuf::from(staging_path);
+    let file = std::fs::File::create(&staging_path).map_err(Error::write(&staging_path))?;
+    staged.push((staging_path.clone(), path.to_path_buf()));
+    let mut writer = std::io::BufWriter::new(file);
+    write(&mut writer)
+        .and_then(|_| writer.flush())
+        .map_err(Error::write(&staging_path))
+}
+
+fn write_section(writer: &mut impl Write, data: &[u8]) -> std::io::Result<()> {
+    writer.write_all(&(data.len() as u64).to_le_bytes())?;
+    writer.write_all(data)
+}
+
+fn read_section(reader: &mut impl Read, path: &Path) -> Result<Vec<u8>> {
+    let mut len = [0; 8];
+    read_or_truncated(reader, &mut len, path)?;
+    let len = u64::from_le_bytes(len);
+    // The length is not trusted to size an allocation up front: a corrupt one
+    // runs into the end of the file instead.
+    let mut data = Vec::new();
+    reader
+        .take(len)
+        .read_to_end(&mut data)
+        .map_err(Error::read(path))?;
+    if data.len() as u64 != len {
+        return Err(Error::corrupt(path, "file is truncated"));
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{map_from_rows, rows_of, temp_dir};
+
+    fn workspace(name: &str) -> Workspace {
+        let workspace = Workspace {
+            workdir: temp_dir(name),
+            ..Workspace::default()
+        };
+        std::fs::create_dir_all(workspace.path(CHECKPOINT_DIR)).unwrap();
+        workspace
+    }
+
+    fn files(workspace: &Workspace) -> Vec<String> {
+        let mut files = std::fs::read_dir(&workspace.workdir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        files.sort_unstable();
+        files
+    }
+
+    #[test]
+    fn restore_gives_back_what_was_captured() {
+        let workspace = workspace("checkpoint-round-trip");
+        let map = map_from_rows(&["0125", "6785"]);
+        workspace.write_map(&map).unwrap();
+        std::fs::write(workspace.shape_db_path(), b"[]").unwrap();
+        std::fs::write(workspace.cache_path(), b"cache").unwrap();
+        let path = checkpoint_path(&workspace, "first");
+        Checkpoint::capture(&workspace)
+            .unwrap()
+            .write(&path)
+            .unwrap();
+
+        let checkpoints = list_checkpoints(&workspace).unwrap();
+        assert_eq!(checkpoints.len(), 1);
+        assert_eq!(checkpoints[0].0, "first");
+        assert_eq!((checkpoints[0].1.width, checkpoints[0].1.height), (4, 2));
+        let before = files(&workspace);
+
+        workspace
+            .write_map(&map_from_rows(&["0000", "0000"]))
+            .unwrap();
+        std::fs::write(workspace.shape_db_path(), b"[{}]").unwrap();
+        std::fs::remove_file(workspace.cache_path()).unwrap();
+        Checkpoint::read(&path)
+            .unwrap()
+            .restore(&workspace)
+            .unwrap();
+
+        assert_eq!(rows_of(&workspace.read_map().unwrap()), rows_of(&map));
+        assert_eq!(std::fs::read(workspace.shape_db_path()).unwrap(), b"[]");
+        assert_eq!(std::fs::read(workspace.cache_path()).unwrap(), b"cache");
+        assert_eq!(files(&workspace), before);
+    }
+
+    #[test]
+    fn restore_removes_a_cache_the_checkpoint_did_not_have() {
+        let workspace = Workspace {
+            map: "map.dat".into(),
+            ..workspace("checkpoint-no-cache")
+        };
+        workspace.write_map(&map_from_rows(&["5"])).unwrap();
+        std::fs::write(workspace.shape_db_path(), b"[]").unwrap();
+        let checkpoint = Checkpoint::capture(&workspace).unwrap();
+        assert!(checkpoint.cached_groups.is_none());
+
+        std::fs::write(workspace.cache_path(), b"cache").unwrap();
+        checkpoint.restore(&workspace).unwrap();
+        assert!(!exists(&workspace.cache_path()).unwrap());
+        assert_eq!(rows_of(&workspace.read_map().unwrap()), ["5"]);
+    }
+
+    #[test]
+    fn huge_section_lengths_are_reported_as_corrupt() {
+        let workspace = workspace("checkpoint-huge-section");
+        let path = checkpoint_path(&workspace, "broken");
+        let mut writer = GzEncoder::new(std::fs::File::create(&path).unwrap(), Compression::fast());
+        writer.write_all(MAGIC).unwrap();
+        writer.write_all(&u64::MAX.to_le_bytes()).unwrap();
+        writer.write_all(b"short").unwrap();
+        writer.finish().unwrap();
+
+        assert!(matches!(
+            Checkpoint::read_meta(&path),
+            Err(Error::Corrupt { .. })
+        ));
+    }
+}
@@ -0,0 +1,156 @@
+use std::{
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use crate::{Tile, solver::InconsistentError};
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug)]
+pub enum Error {
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    Write {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    Json {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    Cbor {
+        path: PathBuf,
+        source: serde_cbor::Error,
+    },
+    Image {
+        path: PathBuf,
+        source: image::ImageError,
+    },
+    /// The file was read, but its contents are not what they should be.
+    Corrupt { path: PathBuf, reason: String },
+    /// Two maps that have to line up have different dimensions.
+    SizeMismatch {
+        path: PathBuf,
+        expected: (usize, usize),
+        found: (usize, usize),
+    },
+    /// Cached groups that were built for a different map or shape database.
+    StaleCache { path: PathBuf, reason: String },
+    /// A map patch does not match the map it is applied to.
+    PatchConflict {
+        path: PathBuf,
+        position: (usize, usize),
+        expected: Tile,
+        found: Tile,
+    },
+    /// The solver ran into a contradiction, described in more detail in
+    /// `report`.
+    Inconsistent {
+        report: PathBuf,
+        source: InconsistentError,
+    },
+    /// A command line argument was rejected.
+    InvalidArgument(String),
+}
+
+impl Error {
+    pub fn read(path: &Path) -> impl FnOnce(std::io::Error) -> Error {
+        let path = path.to_path_buf();
+        move |source| Error::Read { path, source }
+    }
+
+    pub fn write(path: &Path) -> impl FnOnce(std::io::Error) -> Error {
+        let path = path.to_path_buf();
+        move |source| Error::Write { path, source }
+    }
+
+    pub fn corrupt(path: &Path, reason: impl Into<String>) -> Error {
+        Error::Corrupt {
+            path: path.to_path_buf(),
+            reason: reason.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Read { path, source } => {
+                write!(f, "failed to read {}: {source}", path.display())
+            }
+            Error::Write { path, source } => {
+                write!(f, "failed to write {}: {source}", path.display())
+            }
+            Error::Json { path, source } => {
+                write!(f, "failed to parse {}: {source}", path.display())
+            }
+            Error::Cbor { path, source } => {
+                write!(f, "failed to parse {}: {source}", path.display())
+            }
+            Error::Image { path, source } => {
+                write!(f, "failed to write {}: {source}", path.display())
+            }
+            Error::Corrupt { path, reason } => write!(f, "{}: {reason}", path.display()),
+            Error::SizeMismatch {
+                path,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{} is {}x{}, expected {}x{}",
+                path.display(),
+                found.0,
+                found.1,
+                expected.0,
+                expected.1
+            ),
+            Error::StaleCache { path, reason } => write!(
+                f,
+                "{} is stale, {reason}; pass --allow-stale-cache to use it anyway",
+                path.display()
+            ),
+            Error::PatchConflict {
+                path,
+                position: (x, y),
+                expected,
+                found,
+            } => write!(
+                f,
+                "tile at ({x}, {y}) in {} is {found:?}, but the patch expects {expected:?}",
+                path.display()
+            ),
+            Error::Inconsistent { report, source } => {
+                write!(f, "{source}, see {}", report.display())
+            }
+            Error::InvalidArgument(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Read { source, .. } | Error::Write { source, .. } => Some(source),
+            Error::Json { source, .. } => Some(source),
+            Error::Cbor { source, .. } => Some(source),
+            Error::Image { source, .. } => Some(source),
+            Error::Inconsistent { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Turns the result of a tool into its exit code, printing the error if
+/// there is one.
+pub fn report(result: Result<()>) -> ExitCode {
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("error: {error}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,349 @@
+//! Connected components of the [`Tile::Unprocessed`] tiles, computed for the
+//! whole map in one pass.
+//!
+//! Each row is split into runs of consecutive unprocessed tiles, runs that
+//! touch in neighboring rows are merged with a lock-free union-find, and the
+//! result is stored per run rather than per tile. A tile is mapped to its run
+//! in O(1) with a rank query over a bitset of run starts.
+
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use rayon::prelude::*;
+
+use crate::{PackedMap, Tile};
+
+pub type ComponentId = u32;
+
+/// Words per block of the rank directory.
+const RANK_BLOCK_WORDS: usize = 8;
+
+pub struct Labels {
+    width: usize,
+    height: usize,
+    /// `row_offsets[y]..row_offsets[y + 1]` are the runs in row `y`.
+    row_offsets: Vec<usize>,
+    /// First and last x of every run, in row-major order.
+    runs: Vec<(u32, u32)>,
+    /// The component of every run.
+    run_labels: Vec<ComponentId>,
+    /// The first run of every component.
+    component_roots: Vec<u32>,
+    /// One bit per tile, set where a run starts.
+    run_starts: Vec<u64>,
+    /// Number of run starts before each block of `run_starts`.
+    rank_blocks: Vec<u64>,
+}
+
+impl Labels {
+    /// Labels every unprocessed tile of `map` with its 4-connected
+    /// component. Components are numbered in row-major order of their first
+    /// tile.
+    pub fn compute(map: &PackedMap) -> Self {
+        let width = map.width();
+        let height = map.height();
+
+        let row_runs = (0..height)
+            .into_par_iter()
+            .map_init(Vec::new, |row, y| {
+                row.clear();
+                map.unpack_row(y, row);
+                let mut runs = Vec::new();
+                let mut x = 0;
+                while x < width {
+                    if row[x] == Tile::Unprocessed as u8 {
+                        let start = x;
+                        while x + 1 < width && row[x + 1] == Tile::Unprocessed as u8 {
+                            x += 1;
+                        }
+                        runs.push((start as u32, x as u32));
+                    }
+                    x += 1;
+                }
+                runs
+            })
+            .collect::<Vec<_>>();
+
+        let mut row_offsets = Vec::with_capacity(height + 1);
+        row_offsets.push(0);
+        for runs in &row_runs {
+            row_offsets.push(row_offsets.last().unwrap() + runs.len());
+        }
+        let runs = row_runs.concat();
+        drop(row_runs);
+        assert!(
+            runs.len() < u32::MAX as usize,
+            "Too many runs to label: {}",
+            runs.len()
+        );
+
+        // Union every run with the runs it touches in the row above.
+        let parents = (0..runs.len() as u32)
+            .map(AtomicU32::new)
+            .collect::<Vec<_>>();
+        (1..height).into_par_iter().for_each(|y| {
+            let above = row_offsets[y - 1]..row_offsets[y];
+            let mut candidate = above.start;
+            for run in row_offsets[y]..row_offsets[y + 1] {
+                let (x0, x1) = runs[run];
+                while candidate < above.end && runs[candidate].1 < x0 {
+                    candidate += 1;
+                }
+                let mut other = candidate;
+                while other < above.end && runs[other].0 <= x1 {
+                    union(&parents, run as u32, other as u32);
+                    other += 1;
+                }
+            }
+        });
+
+        // Roots are the smallest run of their component, so walking the runs
+        // in order numbers every root before the runs that point to it.
+        let mut run_labels = Vec::with_capacity(runs.len());
+        let mut component_roots = Vec::new();
+        for run in 0..runs.len() {
+            let root = find(&parents, run as u32) as usize;
+            if root == run {
+                run_labels.push(component_roots.len() as ComponentId);
+                component_roots.push(run as u32);
+            } else {
+                run_labels.push(run_labels[root]);
+            }
+        }
+        drop(parents);
+
+        // One spare bit, so that ranks up to and including the last tile never
+        // index past the end.
+        let run_starts = (0..(width * height + 1).div_ceil(64))
+            .map(|_| AtomicU64::new(0))
+            .collect::<Vec<_>>();
+        (0..height).into_par_iter().for_each(|y| {
+            for &(x0, _) in &runs[row_offsets[y]..row_offsets[y + 1]] {
+                let idx = y * width + x0 as usize;
+                run_starts[idx / 64].fetch_or(1 << (idx % 64), Ordering::Relaxed);
+            }
+        });
+        let run_starts = run_starts
+            .into_iter()
+            .map(AtomicU64::into_inner)
+            .collect::<Vec<_>>();
+        let mut rank_blocks = Vec::with_capacity(run_starts.len().div_ceil(RANK_BLOCK_WORDS));
+        let mut rank = 0;
+        for block in run_starts.chunks(RANK_BLOCK_WORDS) {
+            rank_blocks.push(rank);
+            rank += block
+                .iter()
+                .map(|word| word.count_ones() as u64)
+                .sum::<u64>();
+        }
+
+        Labels {
+            width,
+            height,
+            row_offsets,
+            runs,
+            run_labels,
+            component_roots,
+            run_starts,
+            rank_blocks,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn component_count(&self) -> usize {
+        self.component_roots.len()
+    }
+
+    /// Number of run starts at tile indices `< idx`.
+    fn rank(&self, idx: usize) -> usize {
+        let word = idx / 64;
+        let block = word / RANK_BLOCK_WORDS;
+        let mut rank = self.rank_blocks[block] as usize;
+        for &bits in &self.run_starts[block * RANK_BLOCK_WORDS..word] {
+            rank += bits.count_ones() as usize;
+        }
+        if !idx.is_multiple_of(64) {
+            rank += (self.run_starts[word] << (64 - idx % 64)).count_ones() as usize;
+        }
+        rank
+    }
+
+    /// The run containing `(x, y)`, if that tile was unprocessed.
+    fn run_at(&self, x: usize, y: usize) -> Option<usize> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        let runs_before = self.rank(y * self.width + x + 1);
+        if runs_before == self.row_offsets[y] {
+            return None; // No run starts in this row at or before x
+        }
+        let run = runs_before - 1;
+        (self.runs[run].1 as usize >= x).then_some(run)
+    }
+
+    /// The component of `(x, y)`, or `None` if that tile was not
+    /// unprocessed when the labels were computed.
+    pub fn get(&self, x: usize, y: usize) -> Option<ComponentId> {
+        self.run_at(x, y).map(|run| self.run_labels[run])
+    }
+
+    /// The first tile of `component` in row-major order.
+    pub fn first_cell(&self, component: ComponentId) -> (usize, usize) {
+        let run = self.component_roots[component as usize] as usize;
+        let y = self.row_offsets.partition_point(|&offset| offset <= run) - 1;
+        (self.runs[run].0 as usize, y)
+    }
+
+    /// All tiles of `component`.
+    pub fn cells(&self, component: ComponentId) -> Vec<(usize, usize)> {
+        let root = self.component_roots[component as usize] as usize;
+        let root_y = self.row_offsets.partition_point(|&offset| offset <= root) - 1;
+        let mut cells = Vec::new();
+        let mut visited = std::collections::HashSet::from([root]);
+        let mut stack = vec![(root, root_y)];
+        while let Some((run, y)) = stack.pop() {
+            let (x0, x1) = self.runs[run];
+            cells.extend((x0 as usize..=x1 as usize).map(|x| (x, y)));
+            for ny in [y.wrapping_sub(1), y + 1] {
+                if ny >= self.height {
+                    continue;
+                }
+                let row = self.row_offsets[ny]..self.row_offsets[ny + 1];
+                let first = self
+                    .rank(ny * self.width + x0 as usize + 1)
+                    .saturating_sub(1)
+                    .max(row.start);
+                for other in first..row.end {
+                    let (ox0, ox1) = self.runs[other];
+                    if ox0 > x1 {
+                        break;
+                    }
+                    if ox1 >= x0 && visited.insert(other) {
+                        stack.push((other, ny));
+                    }
+                }
+            }
+        }
+        cells
+    }
+}
+
+fn find(parents: &[AtomicU32], mut node: u32) -> u32 {
+    loop {
+        let parent = parents[node as usize].load(Ordering::Relaxed);
+        if parent == node {
+            return node;
+        }
+        // Path halving. Losing the race only means the path stays longer.
+        let grandparent = parents[parent as usize].load(Ordering::Relaxed);
+        let _ = parents[node as usize].compare_exchange(
+            parent,
+            grandparent,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+        node = grandparent;
+    }
+}
+
+/// Links the larger root below the smaller one, so the root of every
+/// component ends up being its smallest run.
+fn union(parents: &[AtomicU32], a: u32, b: u32) {
+    let (mut a, mut b) = (a, b);
+    loop {
+        a = find(parents, a);
+        b = find(parents, b);
+        if a == b {
+            return;
+        }
+        let (small, large) = if a < b { (a, b) } else { (b, a) };
+        if parents[large as usize]
+            .compare_exchange(large, small, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+        {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Labels by flood filling from every unlabeled tile in row-major order.
+    fn naive_labels(map: &PackedMap) -> Vec<Vec<Option<ComponentId>>> {
+        let mut labels = vec![vec![None; map.width()]; map.height()];
+        let mut next = 0;
+        for y in 0..map.height() {
+            for x in 0..map.width() {
+                if map.get(x, y) != Tile::Unprocessed || labels[y][x].is_some() {
+                    continue;
+                }
+                labels[y][x] = Some(next);
+                let mut stack = vec![(x, y)];
+                while let Some((cx, cy)) = stack.pop() {
+                    for (nx, ny) in [
+                        (cx.wrapping_sub(1), cy),
+                        (cx + 1, cy),
+                        (cx, cy.wrapping_sub(1)),
+                        (cx, cy + 1),
+                    ] {
+                        if map.get_checked(nx, ny) == Some(Tile::Unprocessed)
+                            && labels[ny][nx].is_none()
+                        {
+                            labels[ny][nx] = Some(next);
+                            stack.push((nx, ny));
+                        }
+                    }
+                }
+                next += 1;
+            }
+        }
+        labels
+    }
+
+    #[test]
+    fn labels_match_a_flood_fill() {
+        // Widths around multiples of 64 exercise the rank directory edges.
+        for (width, height, density) in [(67, 53, 55), (64, 9, 70), (129, 7, 90), (5, 1, 50)] {
+            let mut map = PackedMap::new(width, height);
+            let mut state = 0x2545f4914f6cdd1du64;
+            for y in 0..height {
+                for x in 0..width {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    if state % 100 < density {
+                        map.set(x, y, Tile::Unprocessed);
+                    }
+                }
+            }
+
+            let labels = Labels::compute(&map);
+            let expected = naive_labels(&map);
+            let mut components = vec![Vec::new(); labels.component_count()];
+            for (y, row) in expected.iter().enumerate() {
+                for (x, &label) in row.iter().enumerate() {
+                    assert_eq!(labels.get(x, y), label, "at ({x}, {y}) in {width}x{height}");
+                    if let Some(label) = label {
+                        components[label as usize].push((x, y));
+                    }
+                }
+            }
+            assert_eq!(labels.get(width, 0), None);
+            for (component, expected_cells) in components.iter().enumerate() {
+                let component = component as ComponentId;
+                assert_eq!(labels.first_cell(component), expected_cells[0]);
+                let mut cells = labels.cells(component);
+                cells.sort_unstable_by_key(|&(x, y)| (y, x));
+                assert_eq!(&cells, expected_cells);
+            }
+        }
+    }
+}
@@ -1,27 +1,736 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
-pub const W: usize = 17268;
-pub const H: usize = 90300;
+pub mod checkpoint;
+mod error;
+pub mod labels;
+pub mod provenance;
+pub mod solver;
+mod tile;
 
-pub type Map = [[u8; W]; H];
+pub use error::{Error, Result, report};
+pub use tile::{InvalidTile, Tile};
 
-pub fn read_map() -> Box<Map> {
-    let data = std::fs::read("puzzlepuzzle.raw").expect("Failed to read puzzlepuzzle.raw");
-    assert_eq!(data.len(), H * W, "Data length mismatch");
-    let boxed: Box<[u8; W * H]> = data
-        .into_boxed_slice()
-        .try_into()
-        .expect("Failed to convert to fixed-size array");
-    let array: Box<Map> = unsafe { std::mem::transmute(boxed) };
-    array
+use solver::InconsistentError;
+
+/// The map with two tiles per byte, high nibble first. This is the same
+/// layout as the body of the `.dat` file.
+#[derive(Clone)]
+pub struct PackedMap {
+    width: usize,
+    height: usize,
+    data: Vec<u8>,
 }
 
-pub fn find_group(map: &Map, x: usize, y: usize) -> Vec<(usize, usize)> {
-    let mut group = Vec::new();
-    let mut stack = vec![(x, y)];
-    let tile = map[y][x];
-    assert_eq!(tile, 5, "Expected tile at ({}, {}) to be 5", x, y);
+impl PackedMap {
+    /// Creates a `width` x `height` map where every tile is [`Tile::Empty`].
+    pub fn new(width: usize, height: usize) -> Self {
+        PackedMap {
+            width,
+            height,
+            data: vec![0; (width * height).div_ceil(2)],
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn in_bounds(&self, x: usize, y: usize) -> bool {
+        x < self.width && y < self.height
+    }
+
+    fn get_nibble(&self, x: usize, y: usize) -> u8 {
+        debug_assert!(self.in_bounds(x, y));
+        let idx = y * self.width + x;
+        let byte = self.data[idx / 2];
+        if idx.is_multiple_of(2) {
+            byte >> 4
+        } else {
+            byte & 0x0F
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> Tile {
+        Tile::from_nibble(self.get_nibble(x, y))
+    }
+
+    /// Like [`PackedMap::get`], but returns `None` outside of the map.
+    pub fn get_checked(&self, x: usize, y: usize) -> Option<Tile> {
+        if self.in_bounds(x, y) {
+            Some(self.get(x, y))
+        } else {
+            None
+        }
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, tile: Tile) {
+        debug_assert!(self.in_bounds(x, y));
+        let value = tile as u8;
+        let idx = y * self.width + x;
+        let byte = &mut self.data[idx / 2];
+        if idx.is_multiple_of(2) {
+            *byte = (*byte & 0x0F) | (value << 4);
+        } else {
+            *byte = (*byte & 0xF0) | value;
+        }
+    }
+
+    /// A content hash of the dimensions and every tile.
+    pub fn fingerprint(&self) -> u64 {
+        let header = encode_header(self.width, self.height);
+        extend_content_hash(content_hash(&header), &self.data)
+    }
+
+    /// Appends row `y` to `out`, one byte per tile.
+    pub fn unpack_row(&self, y: usize, out: &mut Vec<u8>) {
+        out.extend((0..self.width).map(|x| self.get_nibble(x, y)));
+    }
+
+    /// Checks that every nibble is a [`Tile`], so that [`PackedMap::get`]
+    /// never sees anything else. `path` is only used in the error.
+    fn validate(&self, path: &Path) -> Result<()> {
+        let tiles = self.width * self.height;
+        let Some(byte_idx) = self.data[..tiles / 2]
+            .iter()
+            .position(|&byte| !Tile::is_valid_pair(byte))
+            .or_else(|| {
+                // An odd number of tiles leaves the low nibble of the last
+                // byte unused.
+                (!tiles.is_multiple_of(2) && Tile::try_from(self.data[tiles / 2] >> 4).is_err())
+                    .then_some(tiles / 2)
+            })
+        else {
+            return Ok(());
+        };
+        let idx = (byte_idx * 2..byte_idx * 2 + 2)
+            .find(|&idx| {
+                Tile::try_from(self.get_nibble(idx % self.width, idx / self.width)).is_err()
+            })
+            .unwrap();
+        let (x, y) = (idx % self.width, idx / self.width);
+        Err(invalid_tile(path, self.get_nibble(x, y), x, y))
+    }
+}
+
+/// A map of tiles that the solver can read and update.
+pub trait Grid {
+    fn width(&self) -> usize;
+    fn height(&self) -> usize;
+    /// The tile at `(x, y)`, which must be in bounds.
+    fn get(&self, x: usize, y: usize) -> Tile;
+    fn set(&mut self, x: usize, y: usize, tile: Tile);
+
+    fn in_bounds(&self, x: usize, y: usize) -> bool {
+        x < self.width() && y < self.height()
+    }
+
+    /// Like [`Grid::get`], but returns `None` outside of the map.
+    fn get_checked(&self, x: usize, y: usize) -> Option<Tile> {
+        self.in_bounds(x, y).then(|| self.get(x, y))
+    }
+}
+
+impl Grid for PackedMap {
+    fn width(&self) -> usize {
+        self.width
+    }
+
+    fn height(&self) -> usize {
+        self.height
+    }
+
+    fn get(&self, x: usize, y: usize) -> Tile {
+        PackedMap::get(self, x, y)
+    }
+
+    fn set(&mut self, x: usize, y: usize, tile: Tile) {
+        PackedMap::set(self, x, y, tile)
+    }
+}
+
+fn invalid_tile(path: &Path, value: u8, x: usize, y: usize) -> Error {
+    Error::corrupt(path, format!("{} at ({x}, {y})", InvalidTile(value)))
+}
+
+/// Size of the map header: width and height as little-endian `u32`s.
+pub const DAT_HEADER_LEN: usize = 8;
+
+fn encode_header(width: usize, height: usize) -> [u8; DAT_HEADER_LEN] {
+    let mut header = [0; DAT_HEADER_LEN];
+    header[0..4].copy_from_slice(&(width as u32).to_le_bytes());
+    header[4..8].copy_from_slice(&(height as u32).to_le_bytes());
+    header
+}
+
+fn decode_header(header: &[u8], path: &Path) -> Result<(usize, usize)> {
+    if header.len() < DAT_HEADER_LEN {
+        return Err(Error::corrupt(path, "missing width/height header"));
+    }
+    let width = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+    let height = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+    Ok((width, height))
+}
+
+/// Raw maps have no room for a header, so their dimensions are kept in a
+/// sidecar file next to them.
+pub fn raw_header_path(path: &Path) -> PathBuf {
+    let mut header_path = path.as_os_str().to_owned();
+    header_path.push(".hdr");
+    header_path.into()
+}
+
+/// The size of the original puzzle, which raw maps from before the sidecar
+/// header all have.
+const LEGACY_WIDTH: usize = 17268;
+const LEGACY_HEIGHT: usize = 90300;
 
+/// Reads the dimensions of the raw map at `path` from its sidecar header.
+pub fn read_raw_header(path: &Path) -> Result<(usize, usize)> {
+    let header_path = raw_header_path(path);
+    let header = std::fs::read(&header_path).map_err(|source| {
+        if source.kind() != std::io::ErrorKind::NotFound {
+            return Error::read(&header_path)(source);
+        }
+        // Raw maps from before the dimensions were stored have no header.
+        Error::corrupt(
+            path,
+            format!(
+                "{} with the map dimensions is missing. Run dat2raw to regenerate the map and \
+                 its header from the .dat, or to keep the progress in this map, create it with the \
+                 width and height as little-endian u32s ({LEGACY_WIDTH} and \
+                 {LEGACY_HEIGHT} for the original puzzle)",
+                header_path.display()
+            ),
+        )
+    })?;
+    decode_header(&header, &header_path)
+}
+
+fn is_dat(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "dat")
+}
+
+/// Like [`std::fs::exists`], but with our error type.
+pub fn exists(path: &Path) -> Result<bool> {
+    std::fs::exists(path).map_err(Error::read(path))
+}
+
+/// Reads a `.dat` or raw map, picking the format from the extension.
+pub fn read_map_file(path: &Path) -> Result<PackedMap> {
+    if is_dat(path) {
+        read_dat(path)
+    } else {
+        read_raw(path)
+    }
+}
+
+/// Writes a `.dat` or raw map, picking the format from the extension.
+pub fn write_map_file(map: &PackedMap, path: &Path) -> Result<()> {
+    if is_dat(path) {
+        write_dat(map, path)
+    } else {
+        write_map_named(map, path)
+    }
+}
+
+/// Reads a raw map with one byte per tile.
+pub fn read_raw(path: &Path) -> Result<PackedMap> {
+    let (width, height) = read_raw_header(path)?;
+    let file = std::fs::File::open(path).map_err(Error::read(path))?;
+    let len = file.metadata().map_err(Error::read(path))?.len();
+    if len != (width * height) as u64 {
+        return Err(Error::corrupt(
+            path,
+            format!(
+                "expected {} bytes for a {width}x{height} map, found {len}",
+                width * height
+            ),
+        ));
+    }
+
+    // Pack row by row so that the unpacked file never has to fit in memory.
+    let mut reader = std::io::BufReader::new(file);
+    let mut map = PackedMap::new(width, height);
+    let mut row = vec![0; width];
+    for y in 0..height {
+        std::io::Read::read_exact(&mut reader, &mut row).map_err(Error::read(path))?;
+        for (x, &tile) in row.iter().enumerate() {
+            let tile = Tile::try_from(tile).map_err(|_| invalid_tile(path, tile, x, y))?;
+            map.set(x, y, tile);
+        }
+    }
+    Ok(map)
+}
+
+/// Decodes the packed `.dat` format: an 8 byte width/height header followed
+/// by one tile per nibble, high nibble first.
+pub fn read_dat(path: &Path) -> Result<PackedMap> {
+    let file = std::fs::File::open(path).map_err(Error::read(path))?;
+    read_dat_from(&mut std::io::BufReader::new(file), path)
+}
+
+/// Like [`read_dat`], but decodes from any reader. `path` is only used in
+/// error messages.
+pub fn read_dat_from(reader: &mut impl std::io::Read, path: &Path) -> Result<PackedMap> {
+    let mut header = [0; DAT_HEADER_LEN];
+    read_or_truncated(reader, &mut header, path)?;
+    let (width, height) = decode_header(&header, path)?;
+
+    // The body already uses the in-memory layout.
+    let mut map = PackedMap::new(width, height);
+    read_or_truncated(reader, &mut map.data, path)?;
+    if reader.read(&mut [0]).map_err(Error::read(path))? != 0 {
+        return Err(Error::corrupt(
+            path,
+            format!("trailing data after a {width}x{height} map"),
+        ));
+    }
+    map.validate(path)?;
+    Ok(map)
+}
+
+/// `read_exact` that reports running out of data as a truncated file.
+pub(crate) fn read_or_truncated(
+    reader: &mut impl std::io::Read,
+    buf: &mut [u8],
+    path: &Path,
+) -> Result<()> {
+    reader.read_exact(buf).map_err(|source| {
+        if source.kind() == std::io::ErrorKind::UnexpectedEof {
+            Error::corrupt(path, "file is truncated")
+        } else {
+            Error::read(path)(source)
+        }
+    })
+}
+
+/// Writes `map` in the packed `.dat` format understood by [`read_dat`].
+pub fn write_dat(map: &PackedMap, path: &Path) -> Result<()> {
+    let file = std::fs::File::create(path).map_err(Error::write(path))?;
+    let mut writer = std::io::BufWriter::new(file);
+    write_dat_to(map, &mut writer).map_err(Error::write(path))?;
+    std::io::Write::flush(&mut writer).map_err(Error::write(path))?;
+    println!("Written map to {}", path.display());
+    Ok(())
+}
+
+/// Like [`write_dat`], but encodes into any writer.
+pub fn write_dat_to(map: &PackedMap, writer: &mut impl std::io::Write) -> std::io::Result<()> {
+    writer.write_all(&encode_header(map.width, map.height))?;
+    writer.write_all(&map.data)
+}
+
+/// Encodes a map as a raw map, one byte per tile, into any writer. The
+/// dimensions go into the sidecar header, which this does not write.
+pub fn write_raw_to(map: &PackedMap, writer: &mut impl std::io::Write) -> std::io::Result<()> {
+    let mut row = Vec::with_capacity(map.width());
+    for y in 0..map.height() {
+        row.clear();
+        map.unpack_row(y, &mut row);
+        writer.write_all(&row)?;
+    }
+    Ok(())
+}
+
+/// 64 bit FNV-1a. Used for content hashes that are stored on disk, so it must
+/// never change.
+pub fn content_hash(data: &[u8]) -> u64 {
+    extend_content_hash(0xcbf29ce484222325, data)
+}
+
+/// Continues a [`content_hash`] with more data.
+pub fn extend_content_hash(hash: u64, data: &[u8]) -> u64 {
+    data.iter().fold(hash, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+enum MapFormat {
+    Raw,
+    Dat,
+}
+
+/// A map file on disk that is read and patched in place, for tools that only
+/// touch a few tiles and should not load the whole map.
+pub struct MapFile {
+    path: PathBuf,
+    file: std::fs::File,
+    format: MapFormat,
+    width: usize,
+    height: usize,
+}
+
+impl MapFile {
+    /// Opens a `.raw` or `.dat` map, picking the format from the extension.
+    pub fn open(path: &Path, writable: bool) -> Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(writable)
+            .open(path)
+            .map_err(Error::read(path))?;
+        let mut map_file = MapFile {
+            path: path.to_path_buf(),
+            file,
+            format: MapFormat::Raw,
+            width: 0,
+            height: 0,
+        };
+        (map_file.width, map_file.height) = if is_dat(path) {
+            map_file.format = MapFormat::Dat;
+            let mut header = [0; DAT_HEADER_LEN];
+            map_file.read_exact_at(0, &mut header)?;
+            decode_header(&header, path)?
+        } else {
+            read_raw_header(path)?
+        };
+        Ok(map_file)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn in_bounds(&self, x: usize, y: usize) -> bool {
+        x < self.width && y < self.height
+    }
+
+    fn read_exact_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        use std::io::Seek;
+        self.file
+            .seek(std::io::SeekFrom::Start(offset))
+            .map_err(Error::read(&self.path))?;
+        read_or_truncated(&mut self.file, buf, &self.path)
+    }
+
+    fn write_all_at(&mut self, offset: u64, buf: &[u8]) -> Result<()> {
+        use std::io::{Seek, Write};
+        self.file
+            .seek(std::io::SeekFrom::Start(offset))
+            .and_then(|_| self.file.write_all(buf))
+            .map_err(Error::write(&self.path))
+    }
+
+    fn check_in_bounds(&self, x: usize, y: usize) -> Result<()> {
+        if self.in_bounds(x, y) {
+            Ok(())
+        } else {
+            Err(Error::InvalidArgument(format!(
+                "({x}, {y}) is outside of {}, which is {}x{}",
+                self.path.display(),
+                self.width,
+                self.height
+            )))
+        }
+    }
+
+    /// Appends the tiles `x0..x1` of row `y` to `out`, one byte per tile.
+    pub fn read_range(&mut self, y: usize, x0: usize, x1: usize, out: &mut Vec<u8>) -> Result<()> {
+        assert!(x0 <= x1 && x1 <= self.width && y < self.height);
+        let start = y * self.width + x0;
+        let end = y * self.width + x1;
+        match self.format {
+            MapFormat::Raw => {
+                let len = out.len();
+                out.resize(len + end - start, 0);
+                self.read_exact_at(start as u64, &mut out[len..])?;
+            }
+            MapFormat::Dat => {
+                let mut packed = vec![0; end.div_ceil(2) - start / 2];
+                self.read_exact_at((DAT_HEADER_LEN + start / 2) as u64, &mut packed)?;
+                out.extend((start..end).map(|idx| {
+                    let byte = packed[idx / 2 - start / 2];
+                    if idx.is_multiple_of(2) {
+                        byte >> 4
+                    } else {
+                        byte & 0x0F
+                    }
+                }));
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends row `y` to `out`, one byte per tile.
+    pub fn read_row(&mut self, y: usize, out: &mut Vec<u8>) -> Result<()> {
+        self.check_in_bounds(0, y)?;
+        self.read_range(y, 0, self.width, out)
+    }
+
+    /// Reads the tiles `x0..x1` x `y0..y1`, clipped to the map, into a map of
+    /// their own. Tile `(x, y)` ends up at `(x - x0, y - y0)`.
+    pub fn read_window(&mut self, x0: usize, y0: usize, x1: usize, y1: usize) -> Result<PackedMap> {
+        let x0 = x0.min(self.width);
+        let y0 = y0.min(self.height);
+        let x1 = x1.min(self.width).max(x0);
+        let y1 = y1.min(self.height).max(y0);
+        let mut window = PackedMap::new(x1 - x0, y1 - y0);
+        let mut row = Vec::with_capacity(x1 - x0);
+        for y in y0..y1 {
+            row.clear();
+            self.read_range(y, x0, x1, &mut row)?;
+            for (x, &tile) in row.iter().enumerate() {
+                let tile =
+                    Tile::try_from(tile).map_err(|_| invalid_tile(&self.path, tile, x0 + x, y))?;
+                window.set(x, y - y0, tile);
+            }
+        }
+        Ok(window)
+    }
+
+    pub fn get(&mut self, x: usize, y: usize) -> Result<Tile> {
+        self.check_in_bounds(x, y)?;
+        let mut tile = Vec::with_capacity(1);
+        self.read_range(y, x, x + 1, &mut tile)?;
+        Tile::try_from(tile[0]).map_err(|_| invalid_tile(&self.path, tile[0], x, y))
+    }
+
+    /// Overwrites a single tile in the file. The file must have been opened
+    /// as writable.
+    pub fn set(&mut self, x: usize, y: usize, tile: Tile) -> Result<()> {
+        self.check_in_bounds(x, y)?;
+        let value = tile as u8;
+        let idx = y * self.width + x;
+        match self.format {
+            MapFormat::Raw => self.write_all_at(idx as u64, &[value]),
+            MapFormat::Dat => {
+                let offset = (DAT_HEADER_LEN + idx / 2) as u64;
+                let mut byte = [0];
+                self.read_exact_at(offset, &mut byte)?;
+                if idx.is_multiple_of(2) {
+                    byte[0] = (byte[0] & 0x0F) | (value << 4);
+                } else {
+                    byte[0] = (byte[0] & 0xF0) | value;
+                }
+                self.write_all_at(offset, &byte)
+            }
+        }
+    }
+}
+
+/// A changed tile: its position, the old value and the new value.
+pub type TileChange = ((usize, usize), Tile, Tile);
+
+/// The tiles that differ between two maps of the same size.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct MapDiff {
+    pub width: usize,
+    pub height: usize,
+    pub changes: Vec<TileChange>,
+}
+
+impl MapDiff {
+    /// Compares two map files row by row, without loading either of them.
+    pub fn between(old: &mut MapFile, new: &mut MapFile) -> Result<Self> {
+        if (old.width(), old.height()) != (new.width(), new.height()) {
+            return Err(Error::SizeMismatch {
+                path: new.path.clone(),
+                expected: (old.width(), old.height()),
+                found: (new.width(), new.height()),
+            });
+        }
+        let mut changes = Vec::new();
+        let mut old_row = Vec::with_capacity(old.width());
+        let mut new_row = Vec::with_capacity(new.width());
+        for y in 0..old.height() {
+            old_row.clear();
+            new_row.clear();
+            old.read_row(y, &mut old_row)?;
+            new.read_row(y, &mut new_row)?;
+            for (x, (&old_value, &new_value)) in old_row.iter().zip(&new_row).enumerate() {
+                if old_value != new_value {
+                    let old_tile = Tile::try_from(old_value)
+                        .map_err(|_| invalid_tile(&old.path, old_value, x, y))?;
+                    let new_tile = Tile::try_from(new_value)
+                        .map_err(|_| invalid_tile(&new.path, new_value, x, y))?;
+                    changes.push(((x, y), old_tile, new_tile));
+                }
+            }
+        }
+        Ok(MapDiff {
+            width: old.width(),
+            height: old.height(),
+            changes,
+        })
+    }
+
+    /// Returns the diff that undoes this one.
+    pub fn reversed(&self) -> Self {
+        MapDiff {
+            width: self.width,
+            height: self.height,
+            changes: self
+                .changes
+                .iter()
+                .map(|&(pos, old_value, new_value)| (pos, new_value, old_value))
+                .collect(),
+        }
+    }
+
+    /// Writes the new values into `map_file`. Nothing is written unless every
+    /// tile still has its old value.
+    pub fn apply(&self, map_file: &mut MapFile) -> Result<()> {
+        if (map_file.width(), map_file.height()) != (self.width, self.height) {
+            return Err(Error::SizeMismatch {
+                path: map_file.path.clone(),
+                expected: (self.width, self.height),
+                found: (map_file.width(), map_file.height()),
+            });
+        }
+        for &(position, old_value, _) in &self.changes {
+            let found = map_file.get(position.0, position.1)?;
+            if found != old_value {
+                return Err(Error::PatchConflict {
+                    path: map_file.path.clone(),
+                    position,
+                    expected: old_value,
+                    found,
+                });
+            }
+        }
+        for &((x, y), _, new_value) in &self.changes {
+            map_file.set(x, y, new_value)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn read_map_diff(path: &Path) -> Result<MapDiff> {
+    serde_cbor::from_slice(&std::fs::read(path).map_err(Error::read(path))?).map_err(|source| {
+        Error::Cbor {
+            path: path.to_path_buf(),
+            source,
+        }
+    })
+}
+
+pub fn write_map_diff(diff: &MapDiff, path: &Path) -> Result<()> {
+    std::fs::write(
+        path,
+        serde_cbor::to_vec(diff).expect("Failed to serialize map diff"),
+    )
+    .map_err(Error::write(path))?;
+    println!(
+        "Written {} changes to {}",
+        diff.changes.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// Visited set for a flood fill, as a bitset over a window of the map that
+/// grows as the fill reaches past its edges.
+struct LocalBitset {
+    x0: usize,
+    y0: usize,
+    width: usize,
+    height: usize,
+    bits: Vec<u64>,
+}
+
+impl LocalBitset {
+    fn new(x: usize, y: usize) -> Self {
+        const INITIAL_SIZE: usize = 16;
+        let x0 = x.saturating_sub(INITIAL_SIZE / 2);
+        let y0 = y.saturating_sub(INITIAL_SIZE / 2);
+        LocalBitset {
+            x0,
+            y0,
+            width: INITIAL_SIZE,
+            height: INITIAL_SIZE,
+            bits: vec![0; (INITIAL_SIZE * INITIAL_SIZE).div_ceil(64)],
+        }
+    }
+
+    fn contains_window(&self, x: usize, y: usize) -> bool {
+        (self.x0..self.x0 + self.width).contains(&x)
+            && (self.y0..self.y0 + self.height).contains(&y)
+    }
+
+    /// Doubles the window in every direction it has to grow until `(x, y)`
+    /// fits.
+    fn grow_to(&mut self, x: usize, y: usize) {
+        let (mut x0, mut y0, mut x1, mut y1) = (
+            self.x0,
+            self.y0,
+            self.x0 + self.width,
+            self.y0 + self.height,
+        );
+        while x < x0 {
+            x0 = x0.saturating_sub(x1 - x0);
+        }
+        while x >= x1 {
+            x1 += x1 - x0;
+        }
+        while y < y0 {
+            y0 = y0.saturating_sub(y1 - y0);
+        }
+        while y >= y1 {
+            y1 += y1 - y0;
+        }
+        let mut grown = LocalBitset {
+            x0,
+            y0,
+            width: x1 - x0,
+            height: y1 - y0,
+            bits: vec![0; ((x1 - x0) * (y1 - y0)).div_ceil(64)],
+        };
+        for (word_idx, &word) in self.bits.iter().enumerate() {
+            let mut word = word;
+            while word != 0 {
+                let idx = word_idx * 64 + word.trailing_zeros() as usize;
+                grown.insert(self.x0 + idx % self.width, self.y0 + idx / self.width);
+                word &= word - 1;
+            }
+        }
+        *self = grown;
+    }
+
+    /// Marks `(x, y)`, returning whether it was unmarked before.
+    fn insert(&mut self, x: usize, y: usize) -> bool {
+        if !self.contains_window(x, y) {
+            self.grow_to(x, y);
+        }
+        let idx = (y - self.y0) * self.width + (x - self.x0);
+        let mask = 1 << (idx % 64);
+        let was_unset = self.bits[idx / 64] & mask == 0;
+        self.bits[idx / 64] |= mask;
+        was_unset
+    }
+}
+
+/// Flood fills the [`Tile::Unprocessed`] group containing `(x, y)`. `get` returns the
+/// tile at a position, or `None` outside of the map.
+pub fn extract_group(
+    x: usize,
+    y: usize,
+    get: impl Fn(usize, usize) -> Option<Tile>,
+) -> Vec<(usize, usize)> {
+    assert_eq!(
+        get(x, y),
+        Some(Tile::Unprocessed),
+        "Expected tile at ({}, {}) to be unprocessed",
+        x,
+        y
+    );
+    let mut visited = LocalBitset::new(x, y);
+    visited.insert(x, y);
+    let mut group = vec![(x, y)];
+    let mut stack = vec![(x, y)];
     while let Some((cx, cy)) = stack.pop() {
         for (nx, ny) in [
             (cx.wrapping_sub(1), cy), // left
@@ -29,66 +738,178 @@ pub fn find_group(map: &Map, x: usize, y: usize) -> Vec<(usize, usize)> {
             (cx, cy.wrapping_sub(1)), // up
             (cx, cy + 1),             // down
         ] {
-            if nx < W && ny < H && map[ny][nx] == 5 && !group.contains(&(nx, ny)) {
+            if get(nx, ny) == Some(Tile::Unprocessed) && visited.insert(nx, ny) {
                 group.push((nx, ny));
                 stack.push((nx, ny));
             }
         }
     }
-
     group
 }
 
-pub fn normalize_group(group: &[(usize, usize)]) -> (usize, usize, Vec<(usize, usize)>) {
+pub fn find_group(map: &impl Grid, x: usize, y: usize) -> Vec<(usize, usize)> {
+    extract_group(x, y, |x, y| map.get_checked(x, y))
+}
+
+/// One of the 8 rotations and reflections of the grid, as a linear map that
+/// first transposes if bit 2 is set, then negates x if bit 0 is set and y if
+/// bit 1 is set.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Orientation(u8);
+
+impl Orientation {
+    pub const IDENTITY: Orientation = Orientation(0);
+
+    pub fn all() -> impl Iterator<Item = Orientation> {
+        (0..8).map(Orientation)
+    }
+
+    pub fn apply(self, (x, y): (isize, isize)) -> (isize, isize) {
+        let (x, y) = if self.0 & 4 != 0 { (y, x) } else { (x, y) };
+        (
+            if self.0 & 1 != 0 { -x } else { x },
+            if self.0 & 2 != 0 { -y } else { y },
+        )
+    }
+
+    pub fn inverse(self) -> Orientation {
+        Orientation::all()
+            .find(|inverse| inverse.apply(self.apply((1, 2))) == (1, 2))
+            .unwrap()
+    }
+
+    /// Where `point` of a shape ends up on the map when the shape is placed
+    /// at `anchor` in this orientation.
+    pub fn place(self, anchor: (usize, usize), point: (usize, usize)) -> (usize, usize) {
+        let (dx, dy) = self.apply((point.0 as isize, point.1 as isize));
+        (
+            anchor.0.wrapping_add_signed(dx),
+            anchor.1.wrapping_add_signed(dy),
+        )
+    }
+}
+
+/// Moves `group` to the origin and removes rotation and reflection, so every
+/// orientation of a shape gives the same group. Returns the anchor and
+/// orientation that [`Orientation::place`] the canonical group back onto
+/// `group`.
+pub fn canonicalize_group(
+    group: &[(usize, usize)],
+) -> ((usize, usize), Orientation, Vec<(usize, usize)>) {
     assert!(!group.is_empty());
 
-    let min_x = group.iter().map(|(x, _)| *x).min().unwrap();
-    let min_y = group.iter().map(|(_, y)| *y).min().unwrap();
+    let (orientation, (min_x, min_y), canonical) = Orientation::all()
+        .map(|orientation| {
+            let oriented = group
+                .iter()
+                .map(|&(x, y)| orientation.apply((x as isize, y as isize)))
+                .collect::<Vec<_>>();
+            let min_x = oriented.iter().map(|(x, _)| *x).min().unwrap();
+            let min_y = oriented.iter().map(|(_, y)| *y).min().unwrap();
+            let mut normalized = oriented
+                .iter()
+                .map(|(x, y)| ((x - min_x) as usize, (y - min_y) as usize))
+                .collect::<Vec<_>>();
+            normalized.sort_unstable();
+            (orientation, (min_x, min_y), normalized)
+        })
+        .min_by(|a, b| a.2.cmp(&b.2))
+        .unwrap();
 
-    let mut normalized = group
-        .into_iter()
-        .map(|(x, y)| (x - min_x, y - min_y))
-        .collect::<Vec<_>>();
-    normalized.sort_unstable();
-    (min_x, min_y, normalized)
+    // canonical = orientation(cell) - min, so cell = inverse(canonical) + inverse(min).
+    let inverse = orientation.inverse();
+    let (anchor_x, anchor_y) = inverse.apply((min_x, min_y));
+    let anchor = (
+        usize::try_from(anchor_x).expect("Anchor is a corner of the group"),
+        usize::try_from(anchor_y).expect("Anchor is a corner of the group"),
+    );
+    (anchor, inverse, canonical)
 }
 
-pub const UNPROCESSED: u8 = 5;
-pub const NOT_ACTIVE: u8 = 6;
-pub const ACTIVE: u8 = 7;
+pub fn show_at(map: &PackedMap, gx: usize, gy: usize, size: usize) {
+    print!("{}", render_at(map, gx, gy, size, true));
+}
 
-pub fn show_at(map: &Map, gx: usize, gy: usize, size: usize) {
-    for y in gy.saturating_sub(size)..gy.saturating_add(size).min(H) {
-        for x in gx.saturating_sub(size)..gx.saturating_add(size).min(W) {
-            let tile = map[y][x];
+/// The tiles around `(gx, gy)` as text, like [`show_at`] prints them. With
+/// `color`, the tiles are styled with ANSI escapes and the center is
+/// highlighted.
+pub fn render_at(map: &PackedMap, gx: usize, gy: usize, size: usize, color: bool) -> String {
+    let mut out = String::new();
+    for y in gy.saturating_sub(size)..gy.saturating_add(size).min(map.height()) {
+        for x in gx.saturating_sub(size)..gx.saturating_add(size).min(map.width()) {
+            let tile = map.get(x, y);
+            if !color {
+                out.push(tile.plain_glyph());
+                continue;
+            }
             if x == gx && y == gy {
                 // Highlight the center tile
-                print!("\x1b[31;1m"); // Red bold for the center
-            }
-            match tile {
-                0 => print!(" "),
-                1 => print!("0"),
-                2 => print!("1"),
-                3 => print!("2"),
-                UNPROCESSED => print!("#"),
-                NOT_ACTIVE => print!("\x1b[37;2m.\x1b[0m"),
-                ACTIVE => print!("\x1b[33;1m#\x1b[0m"),
-                10 => print!("?"),
-                _ => panic!("Unexpected tile value: {tile}"),
+                out.push_str("\x1b[31;1m"); // Red bold for the center
+            }
+            match tile.ansi_style() {
+                Some(style) => out.push_str(&format!("\x1b[{style}m{}\x1b[0m", tile.glyph())),
+                None => out.push(tile.glyph()),
             }
             if x == gx && y == gy {
                 // Reset color after the center tile
-                print!("\x1b[0m");
+                out.push_str("\x1b[0m");
             }
         }
-        println!();
+        out.push('\n');
     }
+    out
 }
 
 pub const SHAPE_ALPHABET: &str = "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
 
 pub type ShapeDbIndex = HashMap<(Vec<(usize, usize)>, Option<ShapeId>), ShapeId>;
-pub type CachedGroups = HashMap<(usize, usize), ((usize, usize), ShapeId)>;
+/// Where a shape sits on the map: the anchor and orientation to
+/// [`Orientation::place`] its points with.
+pub type Placement = ((usize, usize), ShapeId, Orientation);
+pub type CachedGroups = HashMap<(usize, usize), Placement>;
+
+/// Remembers the placement of the group at each tile, so that the tiles left
+/// over by a deduction keep the child shape they belong to.
+pub trait GroupCache {
+    fn get(&self, position: (usize, usize)) -> Option<Placement>;
+    fn insert(&mut self, position: (usize, usize), placement: Placement);
+
+    /// The tiles of the group containing `(x, y)`, which is not cached yet.
+    fn find_cells(&self, map: &impl Grid, x: usize, y: usize) -> Vec<(usize, usize)> {
+        find_group(map, x, y)
+    }
+
+    /// Called with `shape` as it is before the solver extends its solutions.
+    fn extending_shape(&mut self, _shape_id: ShapeId, _shape: &Shape) {}
+}
+
+impl<C: GroupCache> GroupCache for &mut C {
+    fn get(&self, position: (usize, usize)) -> Option<Placement> {
+        C::get(self, position)
+    }
+
+    fn insert(&mut self, position: (usize, usize), placement: Placement) {
+        C::insert(self, position, placement)
+    }
+
+    fn find_cells(&self, map: &impl Grid, x: usize, y: usize) -> Vec<(usize, usize)> {
+        C::find_cells(self, map, x, y)
+    }
+
+    fn extending_shape(&mut self, shape_id: ShapeId, shape: &Shape) {
+        C::extending_shape(self, shape_id, shape)
+    }
+}
+
+impl GroupCache for CachedGroups {
+    fn get(&self, position: (usize, usize)) -> Option<Placement> {
+        HashMap::get(self, &position).copied()
+    }
+
+    fn insert(&mut self, position: (usize, usize), placement: Placement) {
+        HashMap::insert(self, position, placement);
+    }
+}
 pub type ShapeDb = Vec<Shape>;
 pub type ShapeId = usize;
 
@@ -104,6 +925,434 @@ pub struct Shape {
 
 pub type Solution = Vec<(usize, usize)>;
 
+/// What the solutions of a shape agree on in one spot: the tiles they all set
+/// the same way, in the shape's frame, and the ids of the solutions that fit.
+/// `None` if no solution fits.
+pub type Deduction = Option<(Vec<((usize, usize), Tile)>, Vec<usize>)>;
+
+/// The neighborhood of a placed shape that decides which solutions fit: for
+/// every clue around the shape, how many more active neighbors it needs and
+/// how many unprocessed tiles outside the shape it could still use.
+pub type PatternKey = (ShapeId, Vec<Option<(u8, u8)>>);
+
+/// Memoizes [`Deduction`]s by [`PatternKey`], since the map repeats the same
+/// gadgets over and over.
+#[derive(Default)]
+pub struct DeductionMemo {
+    /// The tiles around every shape, in the shape's frame.
+    boundaries: HashMap<ShapeId, Vec<(isize, isize)>>,
+    deductions: HashMap<PatternKey, Deduction>,
+    pub hits: usize,
+    pub misses: usize,
+}
+
+const DIRECTIONS: [(isize, isize); 4] = [(0, 1), (1, 0), (0, -1), (-1, 0)];
+
+impl DeductionMemo {
+    /// Deduces what the solutions of `shape` agree on where it is placed at
+    /// `anchor` in `orientation` on `map`. The shape must have solutions.
+    pub fn deduce(
+        &mut self,
+        map: &impl Grid,
+        shape_id: ShapeId,
+        shape: &Shape,
+        anchor: (usize, usize),
+        orientation: Orientation,
+    ) -> &Deduction {
+        let boundary = self
+            .boundaries
+            .entry(shape_id)
+            .or_insert_with(|| shape_boundary(&shape.group));
+        let context = clue_context_at(map, &shape.group, boundary, anchor, orientation);
+
+        match self.deductions.entry((shape_id, context)) {
+            std::collections::hash_map::Entry::Occupied(entry) => {
+                self.hits += 1;
+                entry.into_mut()
+            }
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                self.misses += 1;
+                let deduction = deduce_from_context(shape, boundary, &entry.key().1);
+                entry.insert(deduction)
+            }
+        }
+    }
+
+    /// Drops what was deduced for `shape_id`, after its solutions changed.
+    pub fn forget(&mut self, shape_id: ShapeId) {
+        self.deductions.retain(|(id, _), _| *id != shape_id);
+    }
+
+    /// Drops everything known about the shapes from `shape_count` on, after
+    /// they were removed and their ids may be reused.
+    pub fn truncate(&mut self, shape_count: usize) {
+        self.boundaries.retain(|&id, _| id < shape_count);
+        self.deductions.retain(|(id, _), _| *id < shape_count);
+    }
+}
+
+/// The tiles next to `group` that are not part of it, sorted.
+fn shape_boundary(group: &[(usize, usize)]) -> Vec<(isize, isize)> {
+    let mut boundary = group
+        .iter()
+        .flat_map(|&(x, y)| {
+            DIRECTIONS
+                .iter()
+                .map(move |&(dx, dy)| (x as isize + dx, y as isize + dy))
+        })
+        .filter(|&(x, y)| x < 0 || y < 0 || !group.contains(&(x as usize, y as usize)))
+        .collect::<Vec<_>>();
+    boundary.sort_unstable();
+    boundary.dedup();
+    boundary
+}
+
+/// The clue context of `shape` placed at `anchor` in `orientation`, as used
+/// in [`PatternKey`]s.
+pub fn clue_context(
+    map: &impl Grid,
+    shape: &Shape,
+    anchor: (usize, usize),
+    orientation: Orientation,
+) -> Vec<Option<(u8, u8)>> {
+    let boundary = shape_boundary(&shape.group);
+    clue_context_at(map, &shape.group, &boundary, anchor, orientation)
+}
+
+fn clue_context_at(
+    map: &impl Grid,
+    group: &[(usize, usize)],
+    boundary: &[(isize, isize)],
+    anchor: (usize, usize),
+    orientation: Orientation,
+) -> Vec<Option<(u8, u8)>> {
+    let place = |(x, y): (isize, isize)| {
+        let (dx, dy) = orientation.apply((x, y));
+        map.get_checked(
+            anchor.0.wrapping_add_signed(dx),
+            anchor.1.wrapping_add_signed(dy),
+        )
+    };
+    boundary
+        .iter()
+        .map(|&(bx, by)| {
+            let remaining = place((bx, by))?.clue_remaining()?;
+            let available = DIRECTIONS
+                .iter()
+                .filter(|&&(dx, dy)| {
+                    let (nx, ny) = (bx + dx, by + dy);
+                    let in_shape =
+                        nx >= 0 && ny >= 0 && group.contains(&(nx as usize, ny as usize));
+                    !in_shape && place((nx, ny)) == Some(Tile::Unprocessed)
+                })
+                .count();
+            Some((remaining, available as u8))
+        })
+        .collect()
+}
+
+/// Whether a clue can live with `change` of its neighbors in the shape
+/// becoming active: it must not be overfilled, and the tiles it still needs
+/// must be available outside of the shape.
+fn clue_fits(change: u8, clue: Option<(u8, u8)>) -> bool {
+    let Some((remaining, available)) = clue else {
+        return true;
+    };
+    change <= remaining && available >= remaining - change
+}
+
+/// How many neighbors of the boundary tile at `(bx, by)` `solution` makes
+/// active.
+fn clue_change(solution: &[(usize, usize)], (bx, by): (isize, isize)) -> u8 {
+    DIRECTIONS
+        .iter()
+        .filter(|&&(dx, dy)| {
+            let (nx, ny) = (bx + dx, by + dy);
+            nx >= 0 && ny >= 0 && solution.contains(&(nx as usize, ny as usize))
+        })
+        .count() as u8
+}
+
+fn deduce_from_context(
+    shape: &Shape,
+    boundary: &[(isize, isize)],
+    context: &[Option<(u8, u8)>],
+) -> Deduction {
+    let solutions = shape.solutions.as_ref().expect("Shape has no solutions");
+    let mut found_patches: Option<Vec<((usize, usize), Tile)>> = None;
+    let mut used_solutions = Vec::new();
+    for (solution_id, solution) in solutions.iter().enumerate() {
+        let fits = boundary
+            .iter()
+            .zip(context)
+            .all(|(&point, &clue)| clue_fits(clue_change(solution, point), clue));
+        if !fits {
+            continue;
+        }
+        let patches = shape.group.iter().map(|&point| {
+            let value = if solution.contains(&point) {
+                Tile::Active
+            } else {
+                Tile::NotActive
+            };
+            (point, value)
+        });
+        if let Some(found_patches) = &mut found_patches {
+            let patches = patches.collect::<Vec<_>>();
+            found_patches.retain(|patch| patches.contains(patch));
+        } else {
+            found_patches = Some(patches.collect());
+        }
+        used_solutions.push(solution_id);
+    }
+    found_patches.map(|found_patches| (found_patches, used_solutions))
+}
+
+/// Looks up shapes by their group and parent.
+pub fn index_shape_db(shape_db: &[Shape]) -> ShapeDbIndex {
+    shape_db
+        .iter()
+        .enumerate()
+        .map(|(shape_id, shape)| ((shape.group.clone(), shape.parent), shape_id))
+        .collect()
+}
+
+/// Every subset of `group` that fits at least one of `contexts`, the clue
+/// contexts of the places where the shape occurs. A subset that fits only
+/// some occurrences is still needed at those, so only subsets that fit
+/// nowhere are dropped. `None` if there are more than `limit`, since such
+/// shapes are better entered by hand.
+///
+/// Tiles are decided one at a time, and a partial subset is dropped as soon
+/// as no context can be completed, so heavily constrained shapes finish fast
+/// however large they are.
+pub fn enumerate_solutions(
+    group: &[(usize, usize)],
+    contexts: &[Vec<Option<(u8, u8)>>],
+    limit: usize,
+) -> Option<Vec<Solution>> {
+    let boundary = shape_boundary(group);
+    let touching = group
+        .iter()
+        .map(|&(x, y)| {
+            boundary
+                .iter()
+                .enumerate()
+                .filter(|&(_, &(bx, by))| (x as isize - bx).abs() + (y as isize - by).abs() == 1)
+                .map(|(clue, _)| clue)
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let mut undecided = vec![0; boundary.len()];
+    for &clue in touching.iter().flatten() {
+        undecided[clue] += 1;
+    }
+    let mut search = SolutionSearch {
+        group,
+        contexts,
+        touching,
+        active: vec![vec![0; boundary.len()]; contexts.len()],
+        undecided,
+        chosen: vec![false; group.len()],
+        solutions: Vec::new(),
+        limit,
+    };
+    let alive = (0..contexts.len()).collect::<Vec<_>>();
+    search
+        .extend(group.len(), &alive)
+        .then_some(search.solutions)
+}
+
+/// The state of [`enumerate_solutions`]. Tiles are decided from the last one
+/// down, inactive first, so solutions come out in the order of their bitmask.
+struct SolutionSearch<'a> {
+    group: &'a [(usize, usize)],
+    contexts: &'a [Vec<Option<(u8, u8)>>],
+    /// The boundary tiles next to every tile of the group.
+    touching: Vec<Vec<usize>>,
+    /// For every context, how many neighbors of each boundary tile are
+    /// active so far.
+    active: Vec<Vec<u8>>,
+    /// How many neighbors of each boundary tile are not decided yet.
+    undecided: Vec<u8>,
+    chosen: Vec<bool>,
+    solutions: Vec<Solution>,
+    limit: usize,
+}
+
+impl SolutionSearch<'_> {
+    /// Whether the clue at boundary tile `clue` can still be satisfied in
+    /// `context`.
+    fn can_fit(&self, context: usize, clue: usize) -> bool {
+        let Some((remaining, available)) = self.contexts[context][clue] else {
+            return true;
+        };
+        let active = self.active[context][clue];
+        active <= remaining && active + self.undecided[clue] + available >= remaining
+    }
+
+    /// Decides the first `remaining` tiles in every way that keeps one of
+    /// the `alive` contexts satisfiable. Returns `false` once there are more
+    /// than `limit` solutions.
+    fn extend(&mut self, remaining: usize, alive: &[usize]) -> bool {
+        if alive.is_empty() {
+            return true;
+        }
+        let Some(tile) = remaining.checked_sub(1) else {
+            if self.solutions.len() == self.limit {
+                return false;
+            }
+            let solution = (0..self.group.len())
+                .filter(|&i| self.chosen[i])
+                .map(|i| self.group[i])
+                .collect();
+            self.solutions.push(solution);
+            return true;
+        };
+        let touching = std::mem::take(&mut self.touching[tile]);
+        for &clue in &touching {
+            self.undecided[clue] -= 1;
+        }
+        let mut within_limit = true;
+        for active in [false, true] {
+            if active {
+                for counts in &mut self.active {
+                    for &clue in &touching {
+                        counts[clue] += 1;
+                    }
+                }
+            }
+            self.chosen[tile] = active;
+            let still_alive = alive
+                .iter()
+                .copied()
+                .filter(|&context| touching.iter().all(|&clue| self.can_fit(context, clue)))
+                .collect::<Vec<_>>();
+            within_limit = self.extend(tile, &still_alive);
+            if !within_limit {
+                break;
+            }
+        }
+        if self.chosen[tile] {
+            for counts in &mut self.active {
+                for &clue in &touching {
+                    counts[clue] -= 1;
+                }
+            }
+        }
+        self.chosen[tile] = false;
+        for &clue in &touching {
+            self.undecided[clue] += 1;
+        }
+        self.touching[tile] = touching;
+        within_limit
+    }
+}
+
+/// Fingerprint of the first `shape_count` shapes in the database. Only the
+/// groups and parents are hashed, since those are what shape ids in the cache
+/// refer to; adding solutions keeps the fingerprint.
+pub fn shape_db_fingerprint(shape_db: &[Shape], shape_count: usize) -> u64 {
+    shape_db[..shape_count].iter().fold(
+        content_hash(&(shape_count as u64).to_le_bytes()),
+        |hash, shape| {
+            let parent = shape.parent.map_or(u64::MAX, |parent| parent as u64);
+            let hash = extend_content_hash(hash, &parent.to_le_bytes());
+            let hash = extend_content_hash(hash, &(shape.group.len() as u64).to_le_bytes());
+            shape.group.iter().fold(hash, |hash, &(x, y)| {
+                let hash = extend_content_hash(hash, &(x as u64).to_le_bytes());
+                extend_content_hash(hash, &(y as u64).to_le_bytes())
+            })
+        },
+    )
+}
+
+/// The on-disk form of the cached groups, together with fingerprints of the
+/// map and shape database they were built against.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct CachedGroupsFile<G> {
+    map_hash: u64,
+    /// The shape database only ever grows, so the cache stays valid as long
+    /// as its first `shape_count` shapes are unchanged.
+    shape_count: usize,
+    shape_db_hash: u64,
+    groups: G,
+}
+
+/// Reads cached groups and checks that they were built for `map` and
+/// `shape_db`. A mismatch is an error, or only a warning if `allow_stale` is
+/// set.
+pub fn read_cached_groups_named(
+    path: &Path,
+    map: &PackedMap,
+    shape_db: &[Shape],
+    allow_stale: bool,
+) -> Result<CachedGroups> {
+    let data = std::fs::read(path).map_err(Error::read(path))?;
+    let file = match serde_cbor::from_slice::<CachedGroupsFile<CachedGroups>>(&data) {
+        Ok(file) => file,
+        Err(source) => {
+            // Caches written before fingerprints were added are a bare map.
+            let groups = serde_cbor::from_slice(&data).map_err(|_| Error::Cbor {
+                path: path.to_path_buf(),
+                source,
+            })?;
+            eprintln!(
+                "warning: {} has no fingerprints, so it cannot be checked against the map",
+                path.display()
+            );
+            return Ok(groups);
+        }
+    };
+
+    let reason = if file.map_hash != map.fingerprint() {
+        Some("the map has changed since it was written".to_string())
+    } else if file.shape_count > shape_db.len() {
+        Some(format!(
+            "it refers to {} shapes, but the shape database only has {}",
+            file.shape_count,
+            shape_db.len()
+        ))
+    } else if file.shape_db_hash != shape_db_fingerprint(shape_db, file.shape_count) {
+        Some("the shape database has changed since it was written".to_string())
+    } else {
+        None
+    };
+    if let Some(reason) = reason {
+        if !allow_stale {
+            return Err(Error::StaleCache {
+                path: path.to_path_buf(),
+                reason,
+            });
+        }
+        eprintln!("warning: {} is stale: {reason}", path.display());
+    }
+    Ok(file.groups)
+}
+
+/// Writes cached groups along with fingerprints of the `map` and `shape_db`
+/// they belong to.
+pub fn write_cached_groups_named(
+    cached_groups: &CachedGroups,
+    map: &PackedMap,
+    shape_db: &[Shape],
+    path: &Path,
+) -> Result<()> {
+    let file = CachedGroupsFile {
+        map_hash: map.fingerprint(),
+        shape_count: shape_db.len(),
+        shape_db_hash: shape_db_fingerprint(shape_db, shape_db.len()),
+        groups: cached_groups,
+    };
+    std::fs::write(
+        path,
+        serde_cbor::to_vec(&file).expect("Failed to serialize cached groups"),
+    )
+    .map_err(Error::write(path))?;
+    println!("Written cached groups to {}", path.display());
+    Ok(())
+}
+
 pub fn show_shape(shape: &Shape) {
     let group = &shape.group;
     if group.is_empty() {
@@ -156,57 +1405,794 @@ pub fn show_shape(shape: &Shape) {
     }
 }
 
-pub fn read_shape_db() -> ShapeDb {
-    serde_json::from_str::<ShapeDb>(
-        &std::fs::read_to_string("shape_db.json").expect("Failed to read shape_db.json"),
-    )
-    .unwrap()
+/// How many tiles around a contradiction are shown.
+const CONTRADICTION_VIEW_SIZE: usize = 10;
+
+/// Where the tools read and write their files. Relative paths are resolved
+/// against `--workdir`, so several experiments can live side by side.
+#[derive(clap::Args, Debug, Clone)]
+pub struct Workspace {
+    /// Directory that all other paths are relative to
+    #[clap(long, env = "PUZZLE_WORKDIR", default_value = ".", global = true)]
+    pub workdir: PathBuf,
+    /// The working map, `.raw` or `.dat`
+    #[clap(
+        long,
+        env = "PUZZLE_MAP",
+        default_value = "puzzlepuzzle.raw",
+        global = true
+    )]
+    pub map: PathBuf,
+    /// The original puzzle, used while there is no working map yet
+    #[clap(
+        long,
+        env = "PUZZLE_DAT",
+        default_value = "puzzlepuzzle.dat",
+        global = true
+    )]
+    pub dat: PathBuf,
+    #[clap(
+        long,
+        env = "PUZZLE_SHAPE_DB",
+        default_value = "shape_db.json",
+        global = true
+    )]
+    pub shape_db: PathBuf,
+    #[clap(
+        long,
+        env = "PUZZLE_CACHE",
+        default_value = "cached_groups.bin",
+        global = true
+    )]
+    pub cache: PathBuf,
+    /// Where `solve --provenance` records its deductions
+    #[clap(
+        long,
+        env = "PUZZLE_PROVENANCE_LOG",
+        default_value = "provenance.log",
+        global = true
+    )]
+    pub provenance_log: PathBuf,
+    /// Use cached groups even if they were built for a different map or
+    /// shape database
+    #[clap(long, env = "PUZZLE_ALLOW_STALE_CACHE", global = true)]
+    pub allow_stale_cache: bool,
 }
 
-pub fn read_cached_groups() -> CachedGroups {
-    if std::fs::exists("cached_groups.bin").unwrap() {
-        serde_cbor::from_slice(
-            &std::fs::read("cached_groups.bin").expect("Failed to read cached_groups.json"),
-        )
-        .unwrap()
-    } else {
-        Default::default()
+impl Default for Workspace {
+    fn default() -> Self {
+        Workspace {
+            workdir: ".".into(),
+            map: "puzzlepuzzle.raw".into(),
+            dat: "puzzlepuzzle.dat".into(),
+            shape_db: "shape_db.json".into(),
+            cache: "cached_groups.bin".into(),
+            provenance_log: "provenance.log".into(),
+            allow_stale_cache: false,
+        }
     }
 }
 
-pub fn write_shape_db(shape_db: &[Shape]) {
-    std::fs::write("shape_db.json", serde_json::to_string(&shape_db).unwrap()).unwrap();
-    println!("Written to shape_db.json");
-}
+impl Workspace {
+    /// Resolves `name` against the working directory.
+    pub fn path(&self, name: impl AsRef<Path>) -> PathBuf {
+        self.workdir.join(name)
+    }
 
-pub fn write_cached_groups(cached_groups: &CachedGroups) {
-    std::fs::write(
-        "cached_groups.bin",
-        serde_cbor::to_vec(cached_groups).expect("Failed to serialize cached groups"),
-    )
-    .expect("Failed to write cached_groups.bin");
-    println!("Written to cached_groups.bin");
-}
+    pub fn map_path(&self) -> PathBuf {
+        self.path(&self.map)
+    }
 
-pub fn write_cached_groups_named(cached_groups: &CachedGroups, name: &str) {
-    std::fs::write(
-        name,
-        serde_cbor::to_vec(cached_groups).expect("Failed to serialize cached groups"),
-    )
-    .expect("Failed to write {name}");
-    println!("Written cache group to {name}");
+    pub fn dat_path(&self) -> PathBuf {
+        self.path(&self.dat)
+    }
+
+    pub fn shape_db_path(&self) -> PathBuf {
+        self.path(&self.shape_db)
+    }
+
+    pub fn cache_path(&self) -> PathBuf {
+        self.path(&self.cache)
+    }
+
+    pub fn provenance_log_path(&self) -> PathBuf {
+        self.path(&self.provenance_log)
+    }
+
+    /// The working map if it exists, otherwise the original puzzle.
+    pub fn current_map_path(&self) -> Result<PathBuf> {
+        let map_path = self.map_path();
+        if exists(&map_path)? {
+            Ok(map_path)
+        } else {
+            Ok(self.dat_path())
+        }
+    }
+
+    /// Reads the working map, falling back to the original puzzle when no
+    /// working map has been written yet.
+    pub fn read_map(&self) -> Result<PackedMap> {
+        read_map_file(&self.current_map_path()?)
+    }
+
+    /// Opens the map [`Workspace::read_map`] would read for in-place access.
+    pub fn open_map_file(&self) -> Result<MapFile> {
+        MapFile::open(&self.current_map_path()?, false)
+    }
+
+    pub fn write_map(&self, map: &PackedMap) -> Result<()> {
+        write_map_file(map, &self.map_path())
+    }
+
+    /// Shows the map around `error` and writes a report on it to
+    /// `contradiction.txt`, returning that path.
+    pub fn write_contradiction(
+        &self,
+        map: &PackedMap,
+        error: &InconsistentError,
+    ) -> Result<PathBuf> {
+        let (x, y) = error.clue().map_or(error.origin, |(clue, _)| clue);
+        show_at(map, x, y, CONTRADICTION_VIEW_SIZE);
+        let path = self.path("contradiction.txt");
+        let report = format!(
+            "{error}\n\n{}\nAround ({x}, {y}):\n{}",
+            error.report(),
+            render_at(map, x, y, CONTRADICTION_VIEW_SIZE, false)
+        );
+        std::fs::write(&path, report).map_err(Error::write(&path))?;
+        Ok(path)
+    }
+
+    pub fn read_shape_db(&self) -> Result<ShapeDb> {
+        let path = self.shape_db_path();
+        let data = std::fs::read_to_string(&path).map_err(Error::read(&path))?;
+        serde_json::from_str::<ShapeDb>(&data).map_err(|source| Error::Json { path, source })
+    }
+
+    pub fn write_shape_db(&self, shape_db: &[Shape]) -> Result<()> {
+        let path = self.shape_db_path();
+        std::fs::write(&path, serde_json::to_string(&shape_db).unwrap())
+            .map_err(Error::write(&path))?;
+        println!("Written to {}", path.display());
+        Ok(())
+    }
+
+    /// Reads the cached groups, which must have been written for `map` and
+    /// `shape_db`. See [`read_cached_groups_named`].
+    pub fn read_cached_groups(&self, map: &PackedMap, shape_db: &[Shape]) -> Result<CachedGroups> {
+        let path = self.cache_path();
+        if exists(&path)? {
+            read_cached_groups_named(&path, map, shape_db, self.allow_stale_cache)
+        } else {
+            Ok(Default::default())
+        }
+    }
+
+    pub fn write_cached_groups(
+        &self,
+        cached_groups: &CachedGroups,
+        map: &PackedMap,
+        shape_db: &[Shape],
+    ) -> Result<()> {
+        write_cached_groups_named(cached_groups, map, shape_db, &self.cache_path())
+    }
 }
 
-pub fn write_map(map: &Map) {
-    let map: &[[u8; W]; H] = map;
-    let map: &[u8; W * H] = unsafe { core::mem::transmute(map) };
-    std::fs::write("puzzlepuzzle.raw", map).expect("Failed to  write puzzlepuzzle.raw");
-    println!("Written to puzzlepuzzle.raw");
+pub fn write_map_named(map: &PackedMap, path: &Path) -> Result<()> {
+    let file = std::fs::File::create(path).map_err(Error::write(path))?;
+    let mut writer = std::io::BufWriter::new(file);
+    write_raw_to(map, &mut writer)
+        .and_then(|_| std::io::Write::flush(&mut writer))
+        .map_err(Error::write(path))?;
+
+    let header_path = raw_header_path(path);
+    std::fs::write(&header_path, encode_header(map.width(), map.height()))
+        .map_err(Error::write(&header_path))?;
+    println!("Written map to {}", path.display());
+    Ok(())
 }
 
-pub fn write_map_named(map: &Map, name: &str) {
-    let map: &[[u8; W]; H] = map;
-    let map: &[u8; W * H] = unsafe { core::mem::transmute(map) };
-    std::fs::write(&name, map).expect("Failed to  write puzzlepuzzle.raw");
-    println!("Written to map to {name}");
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Builds a map from rows of hex digits, one [`Tile`] value per digit.
+    pub(crate) fn map_from_rows(rows: &[&str]) -> PackedMap {
+        let mut map = PackedMap::new(rows[0].len(), rows.len());
+        for (y, row) in rows.iter().enumerate() {
+            assert_eq!(row.len(), map.width(), "Row {y} has the wrong width");
+            for (x, digit) in row.chars().enumerate() {
+                let value = digit.to_digit(16).expect("Rows are hex digits") as u8;
+                map.set(x, y, Tile::try_from(value).unwrap());
+            }
+        }
+        map
+    }
+
+    pub(crate) fn rows_of(map: &PackedMap) -> Vec<String> {
+        (0..map.height())
+            .map(|y| {
+                (0..map.width())
+                    .map(|x| char::from_digit(map.get(x, y) as u32, 16).unwrap())
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// A fresh directory for the files of one test.
+    pub(crate) fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tools-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn packed_map_keeps_neighboring_nibbles_apart() {
+        let mut map = PackedMap::new(3, 3);
+        map.set(0, 0, Tile::Unknown);
+        map.set(1, 0, Tile::Clue2);
+        map.set(2, 2, Tile::Marked);
+        map.set(1, 0, Tile::Active);
+        assert_eq!(map.get(0, 0), Tile::Unknown);
+        assert_eq!(map.get(1, 0), Tile::Active);
+        assert_eq!(map.get(2, 2), Tile::Marked);
+        assert_eq!(map.get(2, 1), Tile::Empty);
+        assert_eq!(map.get_checked(3, 0), None);
+    }
+
+    #[test]
+    fn dat_round_trip() {
+        let map = map_from_rows(&["123", "567", "8a0"]);
+        let mut bytes = Vec::new();
+        write_dat_to(&map, &mut bytes).unwrap();
+        assert_eq!(bytes.len(), DAT_HEADER_LEN + 5);
+        let read = read_dat_from(&mut bytes.as_slice(), Path::new("map.dat")).unwrap();
+        assert_eq!(rows_of(&read), rows_of(&map));
+        assert_eq!(read.fingerprint(), map.fingerprint());
+    }
+
+    #[test]
+    fn dat_rejects_invalid_tiles_and_trailing_data() {
+        let map = map_from_rows(&["123", "567", "8a0"]);
+        let mut bytes = Vec::new();
+        write_dat_to(&map, &mut bytes).unwrap();
+        let path = Path::new("map.dat");
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(read_dat_from(&mut trailing.as_slice(), path).is_err());
+        assert!(read_dat_from(&mut &bytes[..bytes.len() - 1], path).is_err());
+
+        // Tile 4 does not exist.
+        let mut invalid = bytes.clone();
+        invalid[DAT_HEADER_LEN + 1] = 0x74;
+        assert!(read_dat_from(&mut invalid.as_slice(), path).is_err());
+    }
+
+    #[test]
+    fn raw_round_trip_writes_a_header() {
+        let dir = temp_dir("raw");
+        let path = dir.join("map.raw");
+        let map = map_from_rows(&["1235", "6780"]);
+        write_map_file(&map, &path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), [1, 2, 3, 5, 6, 7, 8, 0]);
+        assert_eq!(read_raw_header(&path).unwrap(), (4, 2));
+        assert_eq!(rows_of(&read_raw(&path).unwrap()), rows_of(&map));
+    }
+
+    #[test]
+    fn map_file_reads_windows_and_sets_tiles() {
+        let dir = temp_dir("map-file");
+        let rows = ["01235", "67801", "23567"];
+        for name in ["map.dat", "map.raw"] {
+            let path = dir.join(name);
+            write_map_file(&map_from_rows(&rows), &path).unwrap();
+            let mut map_file = MapFile::open(&path, true).unwrap();
+            let window = map_file.read_window(1, 1, 4, 9).unwrap();
+            assert_eq!(rows_of(&window), ["780", "356"]);
+            assert_eq!(map_file.read_window(7, 0, 9, 2).unwrap().width(), 0);
+
+            map_file.set(3, 1, Tile::Active).unwrap();
+            assert_eq!(map_file.get(3, 1).unwrap(), Tile::Active);
+            assert_eq!(map_file.get(4, 1).unwrap(), Tile::Clue0);
+            assert!(map_file.get(5, 0).is_err());
+            drop(map_file);
+            assert_eq!(rows_of(&read_map_file(&path).unwrap())[1], "67871");
+        }
+    }
+
+    #[test]
+    fn local_bitset_grows_in_every_direction() {
+        let mut visited = LocalBitset::new(20, 20);
+        let points = [(20, 20), (0, 0), (100, 3), (5, 90), (35, 35), (100, 90)];
+        for &(x, y) in &points {
+            assert!(visited.insert(x, y));
+        }
+        for &(x, y) in &points {
+            assert!(!visited.insert(x, y), "({x}, {y}) was lost when growing");
+        }
+        assert!(visited.insert(21, 20));
+    }
+
+    #[test]
+    fn extract_group_collects_connected_cells() {
+        let map = map_from_rows(&[
+            "0130000", //
+            "2550555", //
+            "0550500", //
+            "0005530", //
+        ]);
+        let mut group = find_group(&map, 1, 1);
+        group.sort_unstable();
+        assert_eq!(group, [(1, 1), (1, 2), (2, 1), (2, 2)]);
+
+        let mut group = find_group(&map, 4, 1);
+        group.sort_unstable();
+        assert_eq!(group, [(3, 3), (4, 1), (4, 2), (4, 3), (5, 1), (6, 1)]);
+    }
+
+    #[test]
+    fn extract_group_follows_long_groups() {
+        // A snake that leaves the initial bitset window several times.
+        let width = 70;
+        let rows = (0..40)
+            .map(|y| match y % 4 {
+                1 | 3 => "5".repeat(width),
+                2 => format!("{}{}", "0".repeat(width - 1), "5"),
+                _ => format!("5{}", "0".repeat(width - 1)),
+            })
+            .collect::<Vec<_>>();
+        let map = map_from_rows(&rows.iter().map(String::as_str).collect::<Vec<_>>());
+        let expected = (0..map.height())
+            .flat_map(|y| (0..map.width()).map(move |x| (x, y)))
+            .filter(|&(x, y)| map.get(x, y) == Tile::Unprocessed)
+            .collect::<Vec<_>>();
+        let mut group = find_group(&map, width - 1, 39);
+        group.sort_unstable_by_key(|&(x, y)| (y, x));
+        assert_eq!(group, expected);
+    }
+
+    #[test]
+    fn orientation_inverse_undoes_it() {
+        for orientation in Orientation::all() {
+            for point in [(1, 2), (-3, 0), (4, -5)] {
+                assert_eq!(orientation.inverse().apply(orientation.apply(point)), point);
+            }
+        }
+    }
+
+    #[test]
+    fn canonicalize_group_is_invariant_under_orientation() {
+        // Has no symmetry, so every orientation is a different group.
+        let shape = [(0, 0), (1, 0), (2, 0), (2, 1), (0, 1), (0, 2), (3, 1)];
+        let mut canonical_groups = Vec::new();
+        for orientation in Orientation::all() {
+            let mut group = shape
+                .iter()
+                .map(|&point| orientation.place((10, 20), point))
+                .collect::<Vec<_>>();
+            group.sort_unstable();
+            let (anchor, placed_with, canonical) = canonicalize_group(&group);
+            let mut placed = canonical
+                .iter()
+                .map(|&point| placed_with.place(anchor, point))
+                .collect::<Vec<_>>();
+            placed.sort_unstable();
+            assert_eq!(placed, group, "{orientation:?} does not place back");
+            canonical_groups.push(canonical);
+        }
+        canonical_groups.dedup();
+        assert_eq!(canonical_groups.len(), 1);
+    }
+
+    /// Every subset in bitmask order, kept if it fits one of `contexts`.
+    fn enumerate_naively(
+        group: &[(usize, usize)],
+        contexts: &[Vec<Option<(u8, u8)>>],
+    ) -> Vec<Solution> {
+        let boundary = shape_boundary(group);
+        (0..1u32 << group.len())
+            .map(|subset| {
+                (0..group.len())
+                    .filter(|&i| subset & 1 << i != 0)
+                    .map(|i| group[i])
+                    .collect::<Solution>()
+            })
+            .filter(|solution| {
+                contexts.iter().any(|context| {
+                    boundary
+                        .iter()
+                        .zip(context)
+                        .all(|(&point, &clue)| clue_fits(clue_change(solution, point), clue))
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn enumerate_solutions_matches_brute_force() {
+        let mut state = 0x9e3779b97f4a7c15u64;
+        let mut random = |bound: u64| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state % bound
+        };
+        for _ in 0..200 {
+            let mut group: Vec<(usize, usize)> = vec![(2, 2)];
+            while group.len() < 1 + random(9) as usize {
+                let (x, y) = group[random(group.len() as u64) as usize];
+                let (dx, dy) = DIRECTIONS[random(4) as usize];
+                let point = (x.wrapping_add_signed(dx), y.wrapping_add_signed(dy));
+                if point.0 < 5 && point.1 < 5 && !group.contains(&point) {
+                    group.push(point);
+                }
+            }
+            let boundary = shape_boundary(&group);
+            let contexts = (0..1 + random(3))
+                .map(|_| {
+                    boundary
+                        .iter()
+                        .map(|_| {
+                            let remaining = random(4) as u8;
+                            (random(3) != 0).then(|| (remaining, random(3) as u8))
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+            let expected = enumerate_naively(&group, &contexts);
+            assert_eq!(
+                enumerate_solutions(&group, &contexts, usize::MAX),
+                Some(expected.clone()),
+                "{group:?} in {contexts:?}"
+            );
+            assert_eq!(
+                enumerate_solutions(&group, &contexts, expected.len()),
+                Some(expected.clone())
+            );
+            if let Some(limit) = expected.len().checked_sub(1) {
+                assert_eq!(enumerate_solutions(&group, &contexts, limit), None);
+            }
+        }
+    }
+
+    #[test]
+    fn enumerate_solutions_keeps_subsets_that_fit_any_occurrence() {
+        let group = [(1, 1)];
+        // The boundary is sorted, so (1, 0) comes second.
+        assert_eq!(shape_boundary(&group)[1], (1, 0));
+        let needs_active = vec![None, Some((1, 0)), None, None];
+        let needs_inactive = vec![None, Some((0, 0)), None, None];
+        assert_eq!(
+            enumerate_solutions(&group, std::slice::from_ref(&needs_active), 8),
+            Some(vec![vec![(1, 1)]])
+        );
+        assert_eq!(
+            enumerate_solutions(&group, &[needs_active, needs_inactive], 8),
+            Some(vec![vec![], vec![(1, 1)]])
+        );
+        assert_eq!(enumerate_solutions(&group, &[], 8), Some(vec![]));
+    }
+
+    #[test]
+    fn enumerate_solutions_finishes_on_large_constrained_shapes() {
+        // A 2x50 strip where every tile has a clue outside of it that needs
+        // exactly that tile, alternating between active and inactive.
+        let group = (0..50).flat_map(|x| [(x, 1), (x, 2)]).collect::<Vec<_>>();
+        let boundary = shape_boundary(&group);
+        let context = boundary
+            .iter()
+            .map(|&(bx, by)| (by == 0 || by == 3).then_some((((bx + by) % 2) as u8, 0)))
+            .collect::<Vec<_>>();
+        let solutions = enumerate_solutions(&group, &[context], 4).unwrap();
+        assert_eq!(solutions.len(), 1);
+        assert_eq!(solutions[0].len(), 50);
+        assert!(enumerate_solutions(&group, &[vec![None; boundary.len()]], 1000).is_none());
+    }
+
+    #[test]
+    fn plain_render_tells_active_from_unprocessed() {
+        let map = map_from_rows(&["25171", "00860"]);
+        assert_eq!(render_at(&map, 2, 1, 3, false), "1#0@0\n  *. \n");
+    }
+
+    #[test]
+    fn map_dimensions_come_from_the_header() {
+        let dir = temp_dir("dimensions");
+        let rows = ["05525", "05505", "00000"];
+        let map = map_from_rows(&rows);
+        for name in ["map.dat", "map.raw"] {
+            let path = dir.join(name);
+            write_map_file(&map, &path).unwrap();
+            let read = read_map_file(&path).unwrap();
+            assert_eq!((read.width(), read.height()), (5, 3));
+            assert_eq!(rows_of(&read), rows);
+        }
+    }
+
+    #[test]
+    fn raw_map_must_match_its_header() {
+        let dir = temp_dir("raw-size");
+        let path = dir.join("map.raw");
+        write_map_file(&map_from_rows(&["0550", "0000"]), &path).unwrap();
+        std::fs::write(&path, [0; 7]).unwrap();
+        assert!(matches!(read_raw(&path), Err(Error::Corrupt { .. })));
+
+        std::fs::remove_file(raw_header_path(&path)).unwrap();
+        let Err(error) = read_raw(&path) else {
+            panic!("Read a raw map without a header");
+        };
+        assert!(error.to_string().contains("dat2raw"), "{error}");
+    }
+
+    #[test]
+    fn map_diff_applies_reverts_and_detects_conflicts() {
+        let dir = temp_dir("map-diff");
+        let old_rows = ["02552", "05550"];
+        let new_rows = ["02762", "06750"];
+        for name in ["map.dat", "map.raw"] {
+            let (old_path, new_path) = (dir.join(format!("old-{name}")), dir.join(name));
+            write_map_file(&map_from_rows(&old_rows), &old_path).unwrap();
+            write_map_file(&map_from_rows(&new_rows), &new_path).unwrap();
+            let diff = MapDiff::between(
+                &mut MapFile::open(&old_path, false).unwrap(),
+                &mut MapFile::open(&new_path, false).unwrap(),
+            )
+            .unwrap();
+            assert_eq!(
+                diff.changes,
+                [
+                    ((2, 0), Tile::Unprocessed, Tile::Active),
+                    ((3, 0), Tile::Unprocessed, Tile::NotActive),
+                    ((1, 1), Tile::Unprocessed, Tile::NotActive),
+                    ((2, 1), Tile::Unprocessed, Tile::Active),
+                ]
+            );
+
+            let mut map_file = MapFile::open(&old_path, true).unwrap();
+            diff.apply(&mut map_file).unwrap();
+            assert_eq!(rows_of(&read_map_file(&old_path).unwrap()), new_rows);
+
+            // Applying it twice finds the new values where the old ones
+            // should be, and leaves the map alone.
+            let Err(Error::PatchConflict {
+                position,
+                expected,
+                found,
+                ..
+            }) = diff.apply(&mut map_file)
+            else {
+                panic!("Applied a diff to a map that already has its changes");
+            };
+            assert_eq!(
+                (position, expected, found),
+                ((2, 0), Tile::Unprocessed, Tile::Active)
+            );
+            assert_eq!(rows_of(&read_map_file(&old_path).unwrap()), new_rows);
+
+            diff.reversed().apply(&mut map_file).unwrap();
+            assert_eq!(rows_of(&read_map_file(&old_path).unwrap()), old_rows);
+        }
+    }
+
+    #[test]
+    fn map_diff_rejects_maps_of_another_size() {
+        let dir = temp_dir("map-diff-size");
+        let (small, large) = (dir.join("small.dat"), dir.join("large.dat"));
+        write_map_file(&map_from_rows(&["05"]), &small).unwrap();
+        write_map_file(&map_from_rows(&["055"]), &large).unwrap();
+        let mut small = MapFile::open(&small, true).unwrap();
+        let mut large = MapFile::open(&large, true).unwrap();
+        assert!(matches!(
+            MapDiff::between(&mut small, &mut large),
+            Err(Error::SizeMismatch { .. })
+        ));
+        let diff = MapDiff {
+            width: 3,
+            height: 1,
+            changes: vec![((2, 0), Tile::Unprocessed, Tile::Active)],
+        };
+        assert!(matches!(
+            diff.apply(&mut small),
+            Err(Error::SizeMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn cached_groups_are_stale_after_setpixel() {
+        let workspace = Workspace {
+            workdir: temp_dir("stale-cache"),
+            ..Workspace::default()
+        };
+        let mut map = map_from_rows(&["02550", "00000"]);
+        workspace.write_map(&map).unwrap();
+        let mut shape_db = vec![Shape {
+            group: vec![(0, 0), (1, 0)],
+            solutions: Some(vec![vec![(0, 0)], vec![(1, 0)]]),
+            parent: None,
+            used_solutions: None,
+        }];
+        let cached_groups = CachedGroups::from([((2, 0), ((2, 0), 0, Orientation::IDENTITY))]);
+        workspace
+            .write_cached_groups(&cached_groups, &map, &shape_db)
+            .unwrap();
+        assert_eq!(
+            workspace.read_cached_groups(&map, &shape_db).unwrap(),
+            cached_groups
+        );
+
+        // New shapes do not invalidate the groups cached for the old ones.
+        shape_db.push(shape_db[0].clone());
+        assert!(workspace.read_cached_groups(&map, &shape_db).is_ok());
+
+        // What setpixel does to an existing working map.
+        let mut map_file = MapFile::open(&workspace.map_path(), true).unwrap();
+        map_file.set(3, 0, Tile::Active).unwrap();
+        let changed = workspace.read_map().unwrap();
+        assert!(matches!(
+            workspace.read_cached_groups(&changed, &shape_db),
+            Err(Error::StaleCache { .. })
+        ));
+        let allowed = Workspace {
+            allow_stale_cache: true,
+            ..workspace.clone()
+        };
+        assert_eq!(
+            allowed.read_cached_groups(&changed, &shape_db).unwrap(),
+            cached_groups
+        );
+
+        // Solutions may change, but groups are what the cache refers to.
+        shape_db[0].solutions = Some(vec![vec![(0, 0)]]);
+        assert!(workspace.read_cached_groups(&map, &shape_db).is_ok());
+        shape_db[0].group = vec![(0, 0), (0, 1)];
+        assert!(matches!(
+            workspace.read_cached_groups(&map, &shape_db),
+            Err(Error::StaleCache { .. })
+        ));
+        map.set(3, 0, Tile::Active);
+        assert_eq!(map.fingerprint(), changed.fingerprint());
+    }
+
+    /// What [`DeductionMemo::deduce`] should find, checked against the map
+    /// for every solution like the solver did before there was a memo.
+    fn deduce_directly(
+        map: &PackedMap,
+        shape: &Shape,
+        anchor: (usize, usize),
+        orientation: Orientation,
+    ) -> Deduction {
+        let cells = shape
+            .group
+            .iter()
+            .map(|&point| orientation.place(anchor, point))
+            .collect::<Vec<_>>();
+        let neighbors = |(x, y): (usize, usize)| {
+            DIRECTIONS
+                .iter()
+                .map(move |&(dx, dy)| (x.wrapping_add_signed(dx), y.wrapping_add_signed(dy)))
+        };
+        let mut found_patches: Option<Vec<((usize, usize), Tile)>> = None;
+        let mut used_solutions = Vec::new();
+        for (solution_id, solution) in shape.solutions.as_ref().unwrap().iter().enumerate() {
+            let active = solution
+                .iter()
+                .map(|&point| orientation.place(anchor, point))
+                .collect::<Vec<_>>();
+            let fits = cells.iter().flat_map(|&cell| neighbors(cell)).all(|clue| {
+                let Some(remaining) = map
+                    .get_checked(clue.0, clue.1)
+                    .and_then(Tile::clue_remaining)
+                else {
+                    return true;
+                };
+                let change = neighbors(clue)
+                    .filter(|point| active.contains(point))
+                    .count() as u8;
+                let available = neighbors(clue)
+                    .filter(|&(x, y)| {
+                        map.get_checked(x, y) == Some(Tile::Unprocessed) && !cells.contains(&(x, y))
+                    })
+                    .count() as u8;
+                change <= remaining && available >= remaining - change
+            });
+            if !fits {
+                continue;
+            }
+            let patches = shape
+                .group
+                .iter()
+                .map(|&point| {
+                    let value = if solution.contains(&point) {
+                        Tile::Active
+                    } else {
+                        Tile::NotActive
+                    };
+                    (point, value)
+                })
+                .collect::<Vec<_>>();
+            match &mut found_patches {
+                Some(found_patches) => found_patches.retain(|patch| patches.contains(patch)),
+                None => found_patches = Some(patches),
+            }
+            used_solutions.push(solution_id);
+        }
+        found_patches.map(|found_patches| (found_patches, used_solutions))
+    }
+
+    #[test]
+    fn memoized_deductions_match_checking_the_map() {
+        let mut state = 0x2545f4914f6cdd1du64;
+        let mut random = |bound: u64| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state % bound
+        };
+        let tiles = [
+            Tile::Empty,
+            Tile::Clue0,
+            Tile::Clue1,
+            Tile::Clue2,
+            Tile::Clue2,
+            Tile::Active,
+            Tile::NotActive,
+            Tile::Unprocessed,
+            Tile::Unprocessed,
+            Tile::Unprocessed,
+        ];
+        let mut shape_db = ShapeDb::new();
+        let mut shape_db_index = ShapeDbIndex::new();
+        let mut memo = DeductionMemo::default();
+        let mut deductions = 0;
+        for _ in 0..300 {
+            let mut map = PackedMap::new(6, 6);
+            for y in 0..6 {
+                for x in 0..6 {
+                    map.set(x, y, tiles[random(tiles.len() as u64) as usize]);
+                }
+            }
+            let mut seen = std::collections::HashSet::new();
+            for (x, y) in (0..6).flat_map(|y| (0..6).map(move |x| (x, y))) {
+                if map.get(x, y) != Tile::Unprocessed || seen.contains(&(x, y)) {
+                    continue;
+                }
+                let cells = find_group(&map, x, y);
+                seen.extend(cells.iter().copied());
+                if cells.len() > 6 {
+                    continue;
+                }
+                let (anchor, orientation, group) = canonicalize_group(&cells);
+                let shape_id = *shape_db_index
+                    .entry((group.clone(), None))
+                    .or_insert_with(|| {
+                        // Every subset, less a few, so that some spots have
+                        // no fitting solution at all.
+                        let boundary = shape_boundary(&group);
+                        let mut solutions =
+                            enumerate_solutions(&group, &[vec![None; boundary.len()]], usize::MAX)
+                                .unwrap();
+                        solutions.retain(|_| random(4) != 0);
+                        shape_db.push(Shape {
+                            group,
+                            solutions: Some(solutions),
+                            parent: None,
+                            used_solutions: None,
+                        });
+                        shape_db.len() - 1
+                    });
+                let shape = &shape_db[shape_id];
+                assert_eq!(
+                    memo.deduce(&map, shape_id, shape, anchor, orientation),
+                    &deduce_directly(&map, shape, anchor, orientation),
+                    "{shape_id} at ({x}, {y}) in {:?}",
+                    rows_of(&map)
+                );
+                deductions += 1;
+            }
+        }
+        assert!(deductions > 1000);
+        assert!(memo.hits > 100, "{} hits", memo.hits);
+    }
 }
@@ -0,0 +1,276 @@
+//! The provenance log records why every deduced tile was set, so a wrong
+//! result can be traced back to the shape that caused it.
+//!
+//! Every run appends its own gzip member holding a stream of CBOR records: a
+//! [`RunMeta`] followed by one [`Deduction`] per group that had tiles set,
+//! and an end marker once the run's results were written. Runs without the
+//! marker never made it to disk and are not used to explain tiles.
+
+use std::{
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use flate2::{Compression, read::MultiGzDecoder, write::GzEncoder};
+
+use crate::{Error, Result, ShapeId, Tile, exists, solver::DeductionEvent};
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct RunMeta {
+    /// Seconds since the Unix epoch.
+    pub created: u64,
+    pub command_line: Vec<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Deduction {
+    /// Numbered from 0 within the run.
+    pub id: usize,
+    pub shape_id: ShapeId,
+    /// A tile of the group.
+    pub origin: (usize, usize),
+    /// The solutions of the shape that fit.
+    pub used_solutions: Vec<usize>,
+    /// The seeded position whose propagation led here.
+    pub seed: (usize, usize),
+    /// The deduction whose patches queued this group, or `None` if the group
+    /// was seeded.
+    pub cause: Option<usize>,
+    /// Position, old tile and new tile of every tile that was set.
+    pub patches: Vec<((usize, usize), Tile, Tile)>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+enum Record {
+    Run(RunMeta),
+    Deduction(Deduction),
+    End,
+}
+
+pub struct Run {
+    pub meta: RunMeta,
+    /// Indexed by [`Deduction::id`].
+    pub deductions: Vec<Deduction>,
+    /// Whether the run was finished, rather than aborted before its map was
+    /// written.
+    pub finished: bool,
+}
+
+/// Appends the deductions of one run to a provenance log.
+pub struct ProvenanceLog {
+    path: PathBuf,
+    writer: GzEncoder<BufWriter<std::fs::File>>,
+    /// The first write error, reported by [`ProvenanceLog::finish`].
+    error: Option<std::io::Error>,
+}
+
+impl ProvenanceLog {
+    /// Starts a new run at the end of the log at `path`.
+    pub fn append(path: &Path) -> Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(Error::write(path))?;
+        let mut log = ProvenanceLog {
+            path: path.to_path_buf(),
+            writer: GzEncoder::new(BufWriter::new(file), Compression::fast()),
+            error: None,
+        };
+        log.write(&Record::Run(RunMeta {
+            created: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            command_line: std::env::args().collect(),
+        }));
+        Ok(log)
+    }
+
+    pub fn record(&mut self, event: &DeductionEvent) {
+        let (_, shape_id, _) = event.placement;
+        self.write(&Record::Deduction(Deduction {
+            id: event.id,
+            shape_id,
+            origin: event.origin,
+            used_solutions: event.used_solutions.clone(),
+            seed: event.seed,
+            cause: event.cause,
+            patches: event
+                .patches
+                .iter()
+                .map(|patch| (patch.position, patch.old, patch.new))
+                .collect(),
+        }));
+    }
+
+    fn write(&mut self, record: &Record) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(error) = serde_cbor::to_writer(&mut self.writer, record) {
+            self.error = Some(std::io::Error::other(error));
+        }
+    }
+
+    /// Completes the run, once what it deduced has been written. A log that
+    /// is dropped instead records the run as aborted.
+    pub fn finish(mut self) -> Result<()> {
+        self.write(&Record::End);
+        let path = self.path;
+        if let Some(error) = self.error {
+            return Err(Error::write(&path)(error));
+        }
+        self.writer
+            .finish()
+            .and_then(|mut writer| writer.flush())
+            .map_err(Error::write(&path))?;
+        println!("Written provenance to {}", path.display());
+        Ok(())
+    }
+}
+
+/// Reads every run in the log at `path`, oldest first. A run that was cut
+/// short ends the log, since nothing after it can be decompressed.
+pub fn read_provenance(path: &Path) -> Result<Vec<Run>> {
+    if !exists(path)? {
+        return Ok(Vec::new());
+    }
+    let file = std::fs::File::open(path).map_err(Error::read(path))?;
+    let reader = MultiGzDecoder::new(BufReader::new(file));
+    let mut runs: Vec<Run> = Vec::new();
+    for record in serde_cbor::Deserializer::from_reader(reader).into_iter::<Record>() {
+        match record {
+            Ok(Record::Run(meta)) => runs.push(Run {
+                meta,
+                deductions: Vec::new(),
+                finished: false,
+            }),
+            Ok(Record::End) => {
+                let Some(run) = runs.last_mut() else {
+                    return Err(Error::corrupt(path, "end of a run before the first run"));
+                };
+                run.finished = true;
+            }
+            Ok(Record::Deduction(deduction)) => {
+                let Some(run) = runs.last_mut().filter(|run| !run.finished) else {
+                    return Err(Error::corrupt(path, "deduction outside of a run"));
+                };
+                if deduction.id != run.deductions.len() {
+                    return Err(Error::corrupt(
+                        path,
+                        format!(
+                            "deduction {} out of order, expected {}",
+                            deduction.id,
+                            run.deductions.len()
+                        ),
+                    ));
+                }
+                run.deductions.push(deduction);
+            }
+            Err(error) if error.is_io() || error.is_eof() => {
+                eprintln!(
+                    "warning: {} is truncated after {} runs: {error}",
+                    path.display(),
+                    runs.len()
+                );
+                break;
+            }
+            Err(source) => {
+                return Err(Error::Cbor {
+                    path: path.to_path_buf(),
+                    source,
+                });
+            }
+        }
+    }
+    Ok(runs)
+}
+
+/// The deductions of finished runs that set `position`, newest first, since
+/// the last one is the one that stuck.
+pub fn deductions_at(
+    runs: &[Run],
+    position: (usize, usize),
+) -> impl Iterator<Item = (&Run, &Deduction)> {
+    runs.iter()
+        .rev()
+        .filter(|run| run.finished)
+        .flat_map(move |run| {
+            run.deductions
+                .iter()
+                .rev()
+                .filter(move |deduction| {
+                    deduction
+                        .patches
+                        .iter()
+                        .any(|&(patched, _, _)| patched == position)
+                })
+                .map(move |deduction| (run, deduction))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Orientation,
+        solver::{DeductionEvent, PatchEvent},
+        tests::temp_dir,
+    };
+
+    fn event(id: usize, position: (usize, usize), new: Tile) -> DeductionEvent {
+        let placement = (position, 0, Orientation::IDENTITY);
+        DeductionEvent {
+            id,
+            placement,
+            origin: position,
+            used_solutions: vec![1],
+            seed: position,
+            cause: None,
+            patches: vec![PatchEvent {
+                position,
+                old: Tile::Unprocessed,
+                new,
+                placement,
+            }],
+        }
+    }
+
+    #[test]
+    fn aborted_runs_do_not_explain_tiles() {
+        let path = temp_dir("provenance").join("provenance.log");
+
+        let mut log = ProvenanceLog::append(&path).unwrap();
+        log.record(&event(0, (4, 1), Tile::Active));
+        log.finish().unwrap();
+
+        // Aborted, so the map never got these.
+        let mut log = ProvenanceLog::append(&path).unwrap();
+        log.record(&event(0, (4, 1), Tile::NotActive));
+        log.record(&event(1, (2, 1), Tile::Active));
+        drop(log);
+
+        let mut log = ProvenanceLog::append(&path).unwrap();
+        log.record(&event(0, (3, 1), Tile::NotActive));
+        log.finish().unwrap();
+
+        let runs = read_provenance(&path).unwrap();
+        assert_eq!(
+            runs.iter().map(|run| run.finished).collect::<Vec<_>>(),
+            [true, false, true]
+        );
+        assert_eq!(runs[1].deductions.len(), 2);
+
+        let found = deductions_at(&runs, (4, 1)).collect::<Vec<_>>();
+        assert_eq!(found.len(), 1);
+        let (_, deduction) = found[0];
+        assert_eq!(
+            deduction.patches,
+            [((4, 1), Tile::Unprocessed, Tile::Active)]
+        );
+        assert_eq!(deduction.used_solutions, [1]);
+        assert_eq!(deductions_at(&runs, (2, 1)).count(), 0);
+        assert_eq!(deductions_at(&runs, (3, 1)).count(), 1);
+    }
+}
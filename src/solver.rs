@@ -0,0 +1,1438 @@
+//! The deduction loop shared by `solve` and `solve_trial`, written against
+//! [`Grid`] so that it runs the same on the full map and on a [`TrailMap`]
+//! that can back out of a branch.
+//! [`Solver`] wraps it for driving a solve step by step.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    CachedGroups, DeductionMemo, Grid, GroupCache, Orientation, Placement, Shape, ShapeDb,
+    ShapeDbIndex, ShapeId, Solution, Tile, canonicalize_group, clue_change, clue_context_at,
+    clue_fits, index_shape_db, labels::Labels, shape_boundary,
+};
+
+/// A tile to set, in map coordinates.
+pub type Patch = ((usize, usize), Tile);
+/// What [`has_locally_unique_solution`] deduced: the patches, and the ids of
+/// the solutions that fit.
+pub type Solved = (Vec<Patch>, Vec<usize>);
+
+/// Why a solution does not fit where its shape is placed.
+#[derive(Debug, Clone)]
+pub struct Rejection {
+    pub solution_id: usize,
+    /// The clue that rules the solution out.
+    pub clue: (usize, usize),
+    pub tile: Tile,
+    /// How many neighbors of the clue the solution makes active.
+    pub change: u8,
+    /// How many unprocessed tiles outside of the shape the clue could still
+    /// use.
+    pub available: u8,
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (x, y) = self.clue;
+        let remaining = self.tile.clue_remaining().unwrap_or_default();
+        write!(
+            f,
+            "solution {}: clue {} at ({x}, {y}) needs {remaining} more active neighbors, ",
+            self.solution_id,
+            self.tile.glyph()
+        )?;
+        if self.change > remaining {
+            write!(f, "but the solution makes {} active", self.change)
+        } else {
+            write!(
+                f,
+                "the solution makes {} active and only {} unprocessed tiles are left around it",
+                self.change, self.available
+            )
+        }
+    }
+}
+
+/// No solution of a shape fits where it is placed.
+#[derive(Debug, Clone)]
+pub struct InconsistentError {
+    /// A tile of the group.
+    pub origin: (usize, usize),
+    pub placement: Placement,
+    /// Every solution of the shape, and why it was ruled out.
+    pub rejections: Vec<Rejection>,
+}
+
+impl InconsistentError {
+    fn new(map: &impl Grid, shape: &Shape, placement: Placement) -> Self {
+        let (anchor, _, orientation) = placement;
+        let boundary = shape_boundary(&shape.group);
+        let context = clue_context_at(map, &shape.group, &boundary, anchor, orientation);
+        let solutions = shape.solutions.as_deref().unwrap_or_default();
+        let rejections = solutions
+            .iter()
+            .enumerate()
+            .filter_map(|(solution_id, solution)| {
+                let (&(bx, by), &clue) = boundary
+                    .iter()
+                    .zip(&context)
+                    .find(|&(&point, &clue)| !clue_fits(clue_change(solution, point), clue))?;
+                let (dx, dy) = orientation.apply((bx, by));
+                let clue_position = (
+                    anchor.0.wrapping_add_signed(dx),
+                    anchor.1.wrapping_add_signed(dy),
+                );
+                let (remaining, available) = clue?;
+                Some(Rejection {
+                    solution_id,
+                    clue: clue_position,
+                    tile: Tile::clue(remaining),
+                    change: clue_change(solution, (bx, by)),
+                    available,
+                })
+            })
+            .collect();
+        InconsistentError {
+            origin: orientation.place(anchor, shape.group[0]),
+            placement,
+            rejections,
+        }
+    }
+
+    pub fn shape_id(&self) -> ShapeId {
+        self.placement.1
+    }
+
+    /// The clue that rules out the most solutions, if any.
+    pub fn clue(&self) -> Option<((usize, usize), Tile)> {
+        let mut counts = HashMap::<_, usize>::new();
+        for rejection in &self.rejections {
+            *counts.entry((rejection.clue, rejection.tile)).or_default() += 1;
+        }
+        counts
+            .into_iter()
+            .max_by_key(|&((position, _), count)| (count, std::cmp::Reverse(position)))
+            .map(|(clue, _)| clue)
+    }
+
+    /// Every rejected solution and why, one per line.
+    pub fn report(&self) -> String {
+        let (anchor, shape_id, orientation) = self.placement;
+        let mut report = format!(
+            "Shape {shape_id} at ({}, {}), anchored at ({}, {}) in {orientation:?}\n",
+            self.origin.0, self.origin.1, anchor.0, anchor.1
+        );
+        if self.rejections.is_empty() {
+            report.push_str("The shape has no solutions\n");
+        }
+        for rejection in &self.rejections {
+            report.push_str(&format!("{rejection}\n"));
+        }
+        report
+    }
+}
+
+impl std::fmt::Display for InconsistentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (x, y) = self.origin;
+        write!(
+            f,
+            "no solution of shape {} fits the group at ({x}, {y})",
+            self.shape_id()
+        )?;
+        if let Some(((cx, cy), tile)) = self.clue() {
+            write!(f, ", clue {} at ({cx}, {cy}) rules out ", tile.glyph())?;
+            let count = self
+                .rejections
+                .iter()
+                .filter(|rejection| rejection.clue == (cx, cy))
+                .count();
+            write!(f, "{count} of {}", self.rejections.len())?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for InconsistentError {}
+
+/// The placement of the group containing `(x, y)`, from the cache or by
+/// looking up its canonical shape.
+pub fn get_group(
+    map: &impl Grid,
+    shape_db_index: &ShapeDbIndex,
+    cached_groups: &mut impl GroupCache,
+    x: usize,
+    y: usize,
+) -> Placement {
+    if let Some(group) = cached_groups.get((x, y)) {
+        group
+    } else {
+        let cells = cached_groups.find_cells(map, x, y);
+        let (anchor, orientation, canonical_group) = canonicalize_group(&cells);
+        let key = (canonical_group, None);
+        let shape_id = *shape_db_index.get(&key).unwrap_or_else(|| {
+            panic!(
+                "Shape not found in index for group at ({}, {}): {:?}",
+                x, y, key.0
+            )
+        });
+        let placement = (anchor, shape_id, orientation);
+        cached_groups.insert((x, y), placement);
+        placement
+    }
+}
+
+/// The tiles of `shape` that every fitting solution agrees on and the ids of
+/// the solutions that fit, or `None` if they agree on no tile. The rest of
+/// the shape is registered as a child shape and cached for its tiles.
+///
+/// With `solution_ids`, only those solutions are considered. What is deduced
+/// from them is not memoized, since it does not hold for the whole shape.
+#[allow(clippy::too_many_arguments)]
+pub fn has_locally_unique_solution(
+    map: &impl Grid,
+    shape_id: ShapeId,
+    shape: &Shape,
+    solution_ids: Option<&[usize]>,
+    shape_db: &mut ShapeDb,
+    shape_db_index: &mut ShapeDbIndex,
+    cached_groups: &mut impl GroupCache,
+    anchor: (usize, usize),
+    orientation: Orientation,
+    memo: &mut DeductionMemo,
+) -> Result<Option<Solved>, InconsistentError> {
+    if shape.solutions.is_none() {
+        return Ok(None); // No solutions available for this shape
+    }
+    let deduction = match solution_ids {
+        None => memo
+            .deduce(map, shape_id, shape, anchor, orientation)
+            .clone(),
+        Some(solution_ids) => DeductionMemo::default()
+            .deduce(
+                map,
+                shape_id,
+                &restrict(shape, solution_ids),
+                anchor,
+                orientation,
+            )
+            .clone()
+            .map(|(patches, used_solutions)| {
+                // Back from positions in the restricted list to solution ids.
+                let used_solutions = used_solutions
+                    .into_iter()
+                    .map(|position| solution_ids[position])
+                    .collect();
+                (patches, used_solutions)
+            }),
+    };
+    let Some((found_patches, used_solutions)) = deduction else {
+        return Err(InconsistentError::new(
+            map,
+            shape,
+            (anchor, shape_id, orientation),
+        ));
+    };
+    let found_patches = found_patches
+        .iter()
+        .map(|&(point, value)| (orientation.place(anchor, point), value))
+        .collect::<Vec<_>>();
+    if found_patches.is_empty() {
+        Ok(None)
+    } else {
+        let mut not_patched = shape
+            .group
+            .iter()
+            .filter_map(|&(x, y)| {
+                let actual = orientation.place(anchor, (x, y));
+                if found_patches.iter().any(|(pos, _value)| *pos == actual) {
+                    None
+                } else {
+                    Some((x, y))
+                }
+            })
+            .collect::<Vec<_>>();
+        if !not_patched.is_empty() {
+            not_patched.sort_unstable();
+            let key = (not_patched, Some(shape_id));
+            if let Some(&child_shape_id) = shape_db_index.get(&key) {
+                cached_groups.extending_shape(child_shape_id, &shape_db[child_shape_id]);
+                if merge_child(&mut shape_db[child_shape_id], shape, &used_solutions) {
+                    memo.forget(child_shape_id);
+                }
+                for not_patched in key.0 {
+                    cached_groups.insert(
+                        orientation.place(anchor, not_patched),
+                        (anchor, child_shape_id, orientation),
+                    );
+                }
+            } else {
+                let child_shape_id = shape_db.len();
+                shape_db_index.insert((key.0.clone(), Some(shape_id)), child_shape_id);
+                shape_db.push(Shape {
+                    group: key.0.clone(),
+                    solutions: Some(restrict_solutions(shape, &used_solutions, &key.0)),
+                    parent: Some(shape_id),
+                    used_solutions: Some(used_solutions.clone()),
+                });
+                for not_patched in key.0 {
+                    cached_groups.insert(
+                        orientation.place(anchor, not_patched),
+                        (anchor, child_shape_id, orientation),
+                    );
+                }
+            }
+        }
+        Ok(Some((found_patches, used_solutions)))
+    }
+}
+
+/// The solutions of `parent` in `used_solutions`, restricted to `group`.
+fn restrict_solutions(
+    parent: &Shape,
+    used_solutions: &[usize],
+    group: &[(usize, usize)],
+) -> Vec<Solution> {
+    let parent_solutions = parent.solutions.as_ref().expect("Parent has no solutions");
+    let mut solutions = Vec::<Solution>::new();
+    for &solution_id in used_solutions {
+        let mut solution = parent_solutions[solution_id]
+            .iter()
+            .copied()
+            .filter(|point| group.contains(point))
+            .collect::<Solution>();
+        solution.sort_unstable();
+        if !solutions.contains(&solution) {
+            solutions.push(solution);
+        }
+    }
+    solutions
+}
+
+/// Adds the parent solutions that survived at another placement of `child`.
+/// New solutions are appended, so the indices grandchildren refer to stay
+/// valid. Returns whether any were added.
+fn merge_child(child: &mut Shape, parent: &Shape, used_solutions: &[usize]) -> bool {
+    let used = child.used_solutions.get_or_insert_with(Vec::new);
+    for &solution_id in used_solutions {
+        if !used.contains(&solution_id) {
+            used.push(solution_id);
+        }
+    }
+    used.sort_unstable();
+
+    let derived = restrict_solutions(parent, used, &child.group);
+    let solutions = child.solutions.get_or_insert_with(Vec::new);
+    let mut known = solutions
+        .iter()
+        .map(|solution| {
+            let mut solution = solution.clone();
+            solution.sort_unstable();
+            solution
+        })
+        .collect::<Vec<_>>();
+    let before = solutions.len();
+    for solution in derived {
+        if !known.contains(&solution) {
+            known.push(solution.clone());
+            solutions.push(solution);
+        }
+    }
+    solutions.len() != before
+}
+
+/// Sets the patched tiles, counts newly active tiles off their neighboring
+/// clues, and queues the tiles around every clue that was touched.
+pub fn apply_patches(map: &mut impl Grid, patches: &[Patch], todo: &mut Vec<(usize, usize)>) {
+    for &((x, y), value) in patches {
+        let old_value = map.get(x, y);
+        map.set(x, y, value);
+
+        if old_value != value {
+            for (dx, dy) in &[(0, 1), (1, 0), (0, -1), (-1, 0)] {
+                let nx = x.wrapping_add_signed(*dx);
+                let ny = y.wrapping_add_signed(*dy);
+                if let Some(remaining) = map.get_checked(nx, ny).and_then(Tile::clue_remaining) {
+                    if value == Tile::Active {
+                        assert!(remaining > 0);
+                        map.set(nx, ny, Tile::clue(remaining - 1)); // Decrease the neighbor tile count
+                    }
+                    for (dx, dy) in &[(0, 1), (1, 0), (0, -1), (-1, 0)] {
+                        let nx = nx.wrapping_add_signed(*dx);
+                        let ny = ny.wrapping_add_signed(*dy);
+                        todo.push((nx, ny));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Solves the group containing `(x, y)` if the tile is still unprocessed.
+/// Returns where the group was placed and what was deduced.
+fn solve_at(
+    map: &impl Grid,
+    (x, y): (usize, usize),
+    shape_db: &mut ShapeDb,
+    shape_db_index: &mut ShapeDbIndex,
+    cached_groups: &mut impl GroupCache,
+    memo: &mut DeductionMemo,
+) -> Result<Option<(Placement, Solved)>, InconsistentError> {
+    if !map.in_bounds(x, y) {
+        return Ok(None);
+    }
+    if map.get(x, y) != Tile::Unprocessed {
+        return Ok(None); // Only process empty tiles
+    }
+
+    let placement @ (anchor, shape_id, orientation) =
+        get_group(map, shape_db_index, cached_groups, x, y);
+    let shape = shape_db[shape_id].clone();
+
+    let solved = has_locally_unique_solution(
+        map,
+        shape_id,
+        &shape,
+        None,
+        shape_db,
+        shape_db_index,
+        cached_groups,
+        anchor,
+        orientation,
+        memo,
+    )?;
+    Ok(solved.map(|solved| (placement, solved)))
+}
+
+/// Solves the groups at the positions in `todo` and everything their
+/// solutions affect, until nothing more can be deduced. Returns how many
+/// groups were solved.
+pub fn propagate(
+    map: &mut impl Grid,
+    todo: &mut Vec<(usize, usize)>,
+    shape_db: &mut ShapeDb,
+    shape_db_index: &mut ShapeDbIndex,
+    cached_groups: &mut impl GroupCache,
+    memo: &mut DeductionMemo,
+) -> Result<usize, InconsistentError> {
+    let mut solved_count = 0;
+    while let Some(position) = todo.pop() {
+        if let Some((_, (patches, _))) =
+            solve_at(map, position, shape_db, shape_db_index, cached_groups, memo)?
+        {
+            solved_count += 1;
+            report_progress(solved_count, todo.len(), position);
+            apply_patches(map, &patches, todo);
+        }
+    }
+    Ok(solved_count)
+}
+
+/// Commits `shape` at `anchor`, restricted to the solutions in
+/// `solution_ids`, and propagates from the tiles that change.
+#[allow(clippy::too_many_arguments)]
+pub fn try_solve(
+    map: &mut impl Grid,
+    shape_id: ShapeId,
+    shape: &Shape,
+    solution_ids: &[usize],
+    shape_db: &mut ShapeDb,
+    shape_db_index: &mut ShapeDbIndex,
+    cached_groups: &mut impl GroupCache,
+    anchor: (usize, usize),
+    orientation: Orientation,
+    memo: &mut DeductionMemo,
+) -> Result<(), InconsistentError> {
+    let mut todo = vec![];
+    if let Some((patches, _)) = has_locally_unique_solution(
+        &*map,
+        shape_id,
+        shape,
+        Some(solution_ids),
+        shape_db,
+        shape_db_index,
+        cached_groups,
+        anchor,
+        orientation,
+        memo,
+    )? {
+        apply_patches(map, &patches, &mut todo);
+    }
+    propagate(
+        map,
+        &mut todo,
+        shape_db,
+        shape_db_index,
+        cached_groups,
+        memo,
+    )?;
+    Ok(())
+}
+
+/// Sets the tiles in `decided` that are still unprocessed. The group of each
+/// is restricted to the solutions that agree with `decided`, and every tile
+/// those agree on is set, with the rest of the group registered as a child
+/// shape like for any deduction. Returns how many groups had tiles set.
+pub fn commit_decided(
+    map: &mut impl Grid,
+    decided: &HashMap<(usize, usize), Tile>,
+    shape_db: &mut ShapeDb,
+    shape_db_index: &mut ShapeDbIndex,
+    cached_groups: &mut impl GroupCache,
+) -> Result<usize, InconsistentError> {
+    let mut positions = decided.keys().copied().collect::<Vec<_>>();
+    positions.sort_unstable();
+    let mut committed = 0;
+    for (x, y) in positions {
+        if map.get(x, y) != Tile::Unprocessed {
+            continue;
+        }
+        let placement = get_group(&*map, shape_db_index, cached_groups, x, y);
+        let (anchor, shape_id, orientation) = placement;
+        let shape = shape_db[shape_id].clone();
+        let Some(solutions) = &shape.solutions else {
+            continue;
+        };
+        let agreeing = (0..solutions.len())
+            .filter(|&solution_id| {
+                shape.group.iter().all(|&point| {
+                    let active = solutions[solution_id].contains(&point);
+                    decided
+                        .get(&orientation.place(anchor, point))
+                        .is_none_or(|&tile| (tile == Tile::Active) == active)
+                })
+            })
+            .collect::<Vec<_>>();
+        if agreeing.is_empty() {
+            return Err(InconsistentError::new(&*map, &shape, placement));
+        }
+        if let Some((patches, _)) = has_locally_unique_solution(
+            &*map,
+            shape_id,
+            &shape,
+            Some(&agreeing),
+            shape_db,
+            shape_db_index,
+            cached_groups,
+            anchor,
+            orientation,
+            &mut DeductionMemo::default(),
+        )? {
+            apply_patches(map, &patches, &mut Vec::new());
+            committed += 1;
+        }
+    }
+    Ok(committed)
+}
+
+/// A map that remembers what every change overwrote, so that a search can
+/// back out of a branch instead of copying the map.
+pub struct TrailMap<'a, G> {
+    map: &'a mut G,
+    trail: Vec<((usize, usize), Tile)>,
+}
+
+impl<'a, G: Grid> TrailMap<'a, G> {
+    pub fn new(map: &'a mut G) -> Self {
+        TrailMap {
+            map,
+            trail: Vec::new(),
+        }
+    }
+
+    /// The point to come back to with [`TrailMap::undo_to`].
+    pub fn mark(&self) -> usize {
+        self.trail.len()
+    }
+
+    /// Reverts every change made since `mark`.
+    pub fn undo_to(&mut self, mark: usize) {
+        for ((x, y), tile) in self.trail.drain(mark..).rev() {
+            self.map.set(x, y, tile);
+        }
+    }
+
+    pub fn map(&self) -> &G {
+        self.map
+    }
+
+    /// The tiles that differ from when the trail was started, with their
+    /// current values.
+    pub fn changes(&self) -> HashMap<(usize, usize), Tile> {
+        let mut original = HashMap::new();
+        for &(position, tile) in &self.trail {
+            original.entry(position).or_insert(tile);
+        }
+        original
+            .into_iter()
+            .filter_map(|((x, y), tile)| {
+                let current = self.map.get(x, y);
+                (current != tile).then_some(((x, y), current))
+            })
+            .collect()
+    }
+}
+
+impl<G: Grid> Grid for TrailMap<'_, G> {
+    fn width(&self) -> usize {
+        self.map.width()
+    }
+
+    fn height(&self) -> usize {
+        self.map.height()
+    }
+
+    fn get(&self, x: usize, y: usize) -> Tile {
+        self.map.get(x, y)
+    }
+
+    fn set(&mut self, x: usize, y: usize, tile: Tile) {
+        self.trail.push(((x, y), self.map.get(x, y)));
+        self.map.set(x, y, tile);
+    }
+}
+
+/// Cached groups that remember what every insertion replaced, the
+/// counterpart of [`TrailMap`]. They also keep the shapes a branch extends,
+/// so undoing the branch can put those back as well.
+pub struct TrailCache<'a> {
+    cached_groups: &'a mut CachedGroups,
+    trail: Vec<TrailEntry>,
+}
+
+enum TrailEntry {
+    Group((usize, usize), Option<Placement>),
+    /// The solutions and used solutions of a shape before they were extended.
+    Shape(ShapeId, Option<Vec<Solution>>, Option<Vec<usize>>),
+}
+
+impl<'a> TrailCache<'a> {
+    pub fn new(cached_groups: &'a mut CachedGroups) -> Self {
+        TrailCache {
+            cached_groups,
+            trail: Vec::new(),
+        }
+    }
+
+    /// The point to come back to with [`TrailCache::undo_to`].
+    pub fn mark(&self) -> usize {
+        self.trail.len()
+    }
+
+    /// Reverts every insertion made since `mark`, and puts back the shapes in
+    /// `shape_db` that were extended since, forgetting what `memo` deduced
+    /// from them in the meantime.
+    pub fn undo_to(&mut self, mark: usize, shape_db: &mut ShapeDb, memo: &mut DeductionMemo) {
+        for entry in self.trail.drain(mark..).rev() {
+            match entry {
+                TrailEntry::Group(position, Some(placement)) => {
+                    self.cached_groups.insert(position, placement);
+                }
+                TrailEntry::Group(position, None) => {
+                    self.cached_groups.remove(&position);
+                }
+                TrailEntry::Shape(shape_id, solutions, used_solutions) => {
+                    let shape = &mut shape_db[shape_id];
+                    shape.solutions = solutions;
+                    shape.used_solutions = used_solutions;
+                    memo.forget(shape_id);
+                }
+            }
+        }
+    }
+
+    pub fn cached_groups(&self) -> &CachedGroups {
+        self.cached_groups
+    }
+}
+
+impl GroupCache for TrailCache<'_> {
+    fn get(&self, position: (usize, usize)) -> Option<Placement> {
+        HashMap::get(self.cached_groups, &position).copied()
+    }
+
+    fn insert(&mut self, position: (usize, usize), placement: Placement) {
+        let replaced = self.cached_groups.insert(position, placement);
+        self.trail.push(TrailEntry::Group(position, replaced));
+    }
+
+    fn extending_shape(&mut self, shape_id: ShapeId, shape: &Shape) {
+        self.trail.push(TrailEntry::Shape(
+            shape_id,
+            shape.solutions.clone(),
+            shape.used_solutions.clone(),
+        ));
+    }
+}
+
+/// A [`GroupCache`] that finds the groups it has not cached in [`Labels`]
+/// instead of flood filling the map. Every deduction caches what is left of
+/// its group, so a group that is not cached is still the component it was
+/// when the labels were computed.
+pub struct LabeledCache<'l, C> {
+    cache: C,
+    labels: Option<&'l Labels>,
+}
+
+impl<'l, C: GroupCache> LabeledCache<'l, C> {
+    /// Without `labels`, groups are flood filled as usual.
+    pub fn new(cache: C, labels: Option<&'l Labels>) -> Self {
+        LabeledCache { cache, labels }
+    }
+}
+
+impl<C: GroupCache> GroupCache for LabeledCache<'_, C> {
+    fn get(&self, position: (usize, usize)) -> Option<Placement> {
+        self.cache.get(position)
+    }
+
+    fn insert(&mut self, position: (usize, usize), placement: Placement) {
+        self.cache.insert(position, placement);
+    }
+
+    fn extending_shape(&mut self, shape_id: ShapeId, shape: &Shape) {
+        self.cache.extending_shape(shape_id, shape);
+    }
+
+    fn find_cells(&self, map: &impl Grid, x: usize, y: usize) -> Vec<(usize, usize)> {
+        let Some((labels, component)) = self
+            .labels
+            .and_then(|labels| Some((labels, labels.get(x, y)?)))
+        else {
+            return self.cache.find_cells(map, x, y);
+        };
+        let cells = labels.cells(component);
+        debug_assert!(
+            cells
+                .iter()
+                .all(|&(x, y)| map.get_checked(x, y) == Some(Tile::Unprocessed)),
+            "Group at ({x}, {y}) changed since the labels were computed"
+        );
+        cells
+    }
+}
+
+/// Drops the shapes added since the database had `shape_count` shapes, along
+/// with everything known about them.
+fn discard_shapes(
+    shape_db: &mut ShapeDb,
+    shape_db_index: &mut ShapeDbIndex,
+    memo: &mut DeductionMemo,
+    shape_count: usize,
+) {
+    if shape_db.len() == shape_count {
+        return;
+    }
+    for shape in shape_db.drain(shape_count..) {
+        shape_db_index.remove(&(shape.group, shape.parent));
+    }
+    memo.truncate(shape_count);
+}
+
+/// `shape` with only the solutions in `solution_ids`.
+fn restrict(shape: &Shape, solution_ids: &[usize]) -> Shape {
+    let solutions = shape.solutions.as_ref().expect("Shape has no solutions");
+    Shape {
+        solutions: Some(
+            solution_ids
+                .iter()
+                .map(|&solution_id| solutions[solution_id].clone())
+                .collect(),
+        ),
+        ..shape.clone()
+    }
+}
+
+fn report_progress(solved_count: usize, todo_len: usize, (x, y): (usize, usize)) {
+    if solved_count.is_multiple_of(10000) {
+        println!("Solved {} tiles, todo.len == {}", solved_count, todo_len);
+        println!("Solving at ({}, {})", x, y);
+    }
+}
+
+/// A tile set by a deduction. Clue counts that change as a consequence are
+/// not reported separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PatchEvent {
+    pub position: (usize, usize),
+    pub old: Tile,
+    pub new: Tile,
+    /// The group the deduction was made for.
+    pub placement: Placement,
+}
+
+/// A group that had tiles deduced.
+#[derive(Debug, Clone)]
+pub struct DeductionEvent {
+    /// Deductions are numbered from 0 in the order they are made.
+    pub id: usize,
+    pub placement: Placement,
+    /// A tile of the group.
+    pub origin: (usize, usize),
+    /// The solutions of the shape that fit.
+    pub used_solutions: Vec<usize>,
+    /// The seeded position whose propagation led here.
+    pub seed: (usize, usize),
+    /// The deduction whose patches queued this group, or `None` if the group
+    /// was seeded.
+    pub cause: Option<usize>,
+    pub patches: Vec<PatchEvent>,
+}
+
+/// A position waiting to be solved: the position, the seed it came from and
+/// the deduction that queued it.
+type Queued = ((usize, usize), (usize, usize), Option<usize>);
+type PatchListener<'a> = Box<dyn FnMut(&PatchEvent) + 'a>;
+type DeductionListener<'a> = Box<dyn FnMut(&DeductionEvent) + 'a>;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SolverStats {
+    /// Groups that had at least one tile deduced.
+    pub solved_groups: usize,
+    pub patched_tiles: usize,
+    /// Child shapes added to the shape database.
+    pub new_shapes: usize,
+    pub memo_hits: usize,
+    pub memo_misses: usize,
+    /// Positions still waiting to be looked at.
+    pub pending: usize,
+    /// Solutions tried by [`Solver::probe`].
+    pub probes: usize,
+    /// Probed solutions that led to a contradiction.
+    pub refuted_probes: usize,
+}
+
+/// Propagates deductions over a [`Grid`], owning the shape database and the
+/// cached groups it extends along the way.
+pub struct Solver<'a, G> {
+    map: G,
+    shape_db: ShapeDb,
+    shape_db_index: ShapeDbIndex,
+    cached_groups: CachedGroups,
+    labels: Option<&'a Labels>,
+    /// Size of the shape database when the solver was created.
+    initial_shape_count: usize,
+    memo: DeductionMemo,
+    todo: Vec<Queued>,
+    /// Unprocessed positions looked at since the last probe, or `None` if
+    /// probing is not enabled.
+    frontier: Option<Vec<(usize, usize)>>,
+    stats: SolverStats,
+    listeners: Vec<PatchListener<'a>>,
+    deduction_listeners: Vec<DeductionListener<'a>>,
+}
+
+impl<'a, G: Grid> Solver<'a, G> {
+    pub fn new(map: G, shape_db: ShapeDb, cached_groups: CachedGroups) -> Self {
+        Solver {
+            map,
+            shape_db_index: index_shape_db(&shape_db),
+            initial_shape_count: shape_db.len(),
+            shape_db,
+            cached_groups,
+            labels: None,
+            memo: DeductionMemo::default(),
+            todo: Vec::new(),
+            frontier: None,
+            stats: SolverStats::default(),
+            listeners: Vec::new(),
+            deduction_listeners: Vec::new(),
+        }
+    }
+
+    /// Calls `listener` for every tile that is set from now on.
+    pub fn on_patch(&mut self, listener: impl FnMut(&PatchEvent) + 'a) {
+        self.listeners.push(Box::new(listener));
+    }
+
+    /// Calls `listener` for every group that has tiles deduced from now on,
+    /// after the listeners for its tiles.
+    pub fn on_deduction(&mut self, listener: impl FnMut(&DeductionEvent) + 'a) {
+        self.deduction_listeners.push(Box::new(listener));
+    }
+
+    /// Finds the groups that are not cached in `labels`, which must have been
+    /// computed for the map the solver was created with.
+    pub fn use_labels(&mut self, labels: &'a Labels) {
+        self.labels = Some(labels);
+    }
+
+    /// Starts remembering the positions [`Solver::probe`] looks at. Without
+    /// this, probing does nothing.
+    pub fn enable_probing(&mut self) {
+        self.frontier.get_or_insert_with(Vec::new);
+    }
+
+    /// Queues positions to solve. Positions outside of the map are ignored.
+    pub fn seed(&mut self, positions: impl IntoIterator<Item = (usize, usize)>) {
+        let map = &self.map;
+        self.todo.extend(
+            positions
+                .into_iter()
+                .filter(|&(x, y)| map.in_bounds(x, y))
+                .map(|position| (position, position, None)),
+        );
+    }
+
+    /// Looks at the next queued position and applies what can be deduced
+    /// there. Returns `false` once the queue is empty.
+    pub fn step(&mut self) -> Result<bool, InconsistentError> {
+        let Some((position, seed, cause)) = self.todo.pop() else {
+            return Ok(false);
+        };
+        if let Some(frontier) = &mut self.frontier
+            && self.map.get_checked(position.0, position.1) == Some(Tile::Unprocessed)
+        {
+            frontier.push(position);
+        }
+        let Some((placement, (patches, used_solutions))) = solve_at(
+            &self.map,
+            position,
+            &mut self.shape_db,
+            &mut self.shape_db_index,
+            &mut LabeledCache::new(&mut self.cached_groups, self.labels),
+            &mut self.memo,
+        )?
+        else {
+            return Ok(true);
+        };
+        report_progress(self.stats.solved_groups + 1, self.todo.len(), position);
+        self.commit(placement, &patches, used_solutions, seed, cause);
+        Ok(true)
+    }
+
+    /// Applies the patches deduced for the group at `placement`, queues the
+    /// positions they affect and tells the listeners.
+    fn commit(
+        &mut self,
+        placement: Placement,
+        patches: &[Patch],
+        used_solutions: Vec<usize>,
+        seed: (usize, usize),
+        cause: Option<usize>,
+    ) {
+        let id = self.stats.solved_groups;
+        self.stats.solved_groups += 1;
+        let events = patches
+            .iter()
+            .filter_map(|&(position, new)| {
+                let old = self.map.get(position.0, position.1);
+                (old != new).then_some(PatchEvent {
+                    position,
+                    old,
+                    new,
+                    placement,
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut queued = Vec::new();
+        apply_patches(&mut self.map, patches, &mut queued);
+        self.todo.extend(
+            queued
+                .into_iter()
+                .map(|position| (position, seed, Some(id))),
+        );
+        self.stats.patched_tiles += events.len();
+        for event in &events {
+            for listener in &mut self.listeners {
+                listener(event);
+            }
+        }
+        if !self.deduction_listeners.is_empty() {
+            let (anchor, shape_id, orientation) = placement;
+            let event = DeductionEvent {
+                id,
+                placement,
+                origin: orientation.place(anchor, self.shape_db[shape_id].group[0]),
+                used_solutions,
+                seed,
+                cause,
+                patches: events,
+            };
+            for listener in &mut self.deduction_listeners {
+                listener(&event);
+            }
+        }
+    }
+
+    /// Steps until nothing more can be deduced.
+    pub fn propagate(&mut self) -> Result<(), InconsistentError> {
+        while self.step()? {}
+        Ok(())
+    }
+
+    /// Tries every solution of the unresolved groups propagation has looked
+    /// at since the last probe, and drops the ones that lead to a
+    /// contradiction. The tiles all remaining solutions agree on are
+    /// committed like a deduction, and the positions around them queued.
+    /// Returns how many groups had tiles committed, which is always 0 unless
+    /// [`Solver::enable_probing`] was called.
+    pub fn probe(&mut self) -> Result<usize, InconsistentError> {
+        let Some(mut frontier) = self.frontier.as_mut().map(std::mem::take) else {
+            return Ok(0);
+        };
+        frontier.sort_unstable();
+        frontier.dedup();
+        let mut probed = HashSet::new();
+        let mut committed = 0;
+        for (x, y) in frontier {
+            if self.map.get(x, y) != Tile::Unprocessed {
+                continue;
+            }
+            let placement = get_group(
+                &self.map,
+                &self.shape_db_index,
+                &mut LabeledCache::new(&mut self.cached_groups, self.labels),
+                x,
+                y,
+            );
+            if !probed.insert(placement) {
+                continue;
+            }
+            let (anchor, shape_id, orientation) = placement;
+            let shape = self.shape_db[shape_id].clone();
+            let Some(solutions) = &shape.solutions else {
+                continue;
+            };
+
+            let mut surviving = Vec::new();
+            let mut refuted = None;
+            let mut map = TrailMap::new(&mut self.map);
+            let mut cached_groups = TrailCache::new(&mut self.cached_groups);
+            let shape_count = self.shape_db.len();
+            for solution_id in 0..solutions.len() {
+                self.stats.probes += 1;
+                match try_solve(
+                    &mut map,
+                    shape_id,
+                    &shape,
+                    &[solution_id],
+                    &mut self.shape_db,
+                    &mut self.shape_db_index,
+                    &mut LabeledCache::new(&mut cached_groups, self.labels),
+                    anchor,
+                    orientation,
+                    &mut self.memo,
+                ) {
+                    Ok(()) => surviving.push(solution_id),
+                    Err(error) => {
+                        self.stats.refuted_probes += 1;
+                        refuted = Some(error);
+                    }
+                }
+                map.undo_to(0);
+                cached_groups.undo_to(0, &mut self.shape_db, &mut self.memo);
+                // The child shapes of a probe only existed in its branch.
+                discard_shapes(
+                    &mut self.shape_db,
+                    &mut self.shape_db_index,
+                    &mut self.memo,
+                    shape_count,
+                );
+            }
+            if surviving.is_empty() {
+                return Err(
+                    refuted.unwrap_or_else(|| InconsistentError::new(&self.map, &shape, placement))
+                );
+            }
+            if surviving.len() == solutions.len() {
+                continue;
+            }
+
+            let Some((patches, _)) = has_locally_unique_solution(
+                &self.map,
+                shape_id,
+                &shape,
+                Some(&surviving),
+                &mut self.shape_db,
+                &mut self.shape_db_index,
+                &mut self.cached_groups,
+                anchor,
+                orientation,
+                &mut self.memo,
+            )?
+            else {
+                continue;
+            };
+            self.commit(placement, &patches, surviving, (x, y), None);
+            committed += 1;
+        }
+        Ok(committed)
+    }
+
+    pub fn stats(&self) -> SolverStats {
+        SolverStats {
+            new_shapes: self.shape_db.len() - self.initial_shape_count,
+            memo_hits: self.memo.hits,
+            memo_misses: self.memo.misses,
+            pending: self.todo.len(),
+            ..self.stats
+        }
+    }
+
+    pub fn map(&self) -> &G {
+        &self.map
+    }
+
+    pub fn shape_db(&self) -> &ShapeDb {
+        &self.shape_db
+    }
+
+    pub fn cached_groups(&self) -> &CachedGroups {
+        &self.cached_groups
+    }
+
+    /// Gives back the map, shape database and cached groups, to be written
+    /// out.
+    pub fn into_parts(self) -> (G, ShapeDb, CachedGroups) {
+        (self.map, self.shape_db, self.cached_groups)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{
+        PackedMap,
+        tests::{map_from_rows, rows_of},
+    };
+
+    /// A single tile and a domino, each with every solution.
+    fn shape_db() -> ShapeDb {
+        let shape = |group: Vec<(usize, usize)>, solutions: Vec<Solution>| Shape {
+            group,
+            solutions: Some(solutions),
+            parent: None,
+            used_solutions: None,
+        };
+        vec![
+            shape(vec![(0, 0)], vec![vec![], vec![(0, 0)]]),
+            shape(
+                vec![(0, 0), (0, 1)],
+                vec![vec![], vec![(0, 0)], vec![(0, 1)], vec![(0, 0), (0, 1)]],
+            ),
+        ]
+    }
+
+    fn solver<'a>(rows: &[&str]) -> Solver<'a, PackedMap> {
+        Solver::new(map_from_rows(rows), shape_db(), CachedGroups::new())
+    }
+
+    #[test]
+    fn propagate_follows_patches_to_other_groups() {
+        let mut solver = solver(&["25250"]);
+        let patches = Rc::new(RefCell::new(Vec::new()));
+        let deductions = Rc::new(RefCell::new(Vec::new()));
+        solver.on_patch({
+            let patches = patches.clone();
+            move |event| patches.borrow_mut().push(*event)
+        });
+        solver.on_deduction({
+            let deductions = deductions.clone();
+            move |event| deductions.borrow_mut().push(event.clone())
+        });
+        solver.seed([(1, 0), (7, 0)]);
+        assert_eq!(solver.stats().pending, 1);
+
+        assert!(solver.step().unwrap());
+        assert_eq!(solver.map().get(1, 0), Tile::Active);
+        assert_eq!(solver.map().get(0, 0), Tile::Clue0);
+        assert_eq!(solver.map().get(2, 0), Tile::Clue0);
+        assert_eq!(solver.map().get(3, 0), Tile::Unprocessed);
+
+        solver.propagate().unwrap();
+        assert!(!solver.step().unwrap());
+        assert_eq!(solver.map().get(3, 0), Tile::NotActive);
+
+        let patches = patches.borrow();
+        assert_eq!(
+            patches
+                .iter()
+                .map(|event| (event.position, event.old, event.new))
+                .collect::<Vec<_>>(),
+            [
+                ((1, 0), Tile::Unprocessed, Tile::Active),
+                ((3, 0), Tile::Unprocessed, Tile::NotActive),
+            ]
+        );
+        let deductions = deductions.borrow();
+        assert_eq!(deductions.len(), 2);
+        assert_eq!(
+            (deductions[0].id, deductions[0].seed, deductions[0].cause),
+            (0, (1, 0), None)
+        );
+        assert_eq!(deductions[0].used_solutions, [1]);
+        assert_eq!(
+            (deductions[1].id, deductions[1].seed, deductions[1].cause),
+            (1, (1, 0), Some(0))
+        );
+        assert_eq!(deductions[1].origin, (3, 0));
+        assert_eq!(deductions[1].used_solutions, [0]);
+
+        let stats = solver.stats();
+        assert_eq!((stats.solved_groups, stats.patched_tiles), (2, 2));
+        assert_eq!((stats.new_shapes, stats.pending), (0, 0));
+    }
+
+    #[test]
+    fn partial_deduction_registers_a_child_shape() {
+        let mut solver = solver(&["25500"]);
+        solver.seed([(1, 0)]);
+        solver.propagate().unwrap();
+        assert_eq!(solver.map().get(1, 0), Tile::Active);
+        assert_eq!(solver.map().get(2, 0), Tile::Unprocessed);
+        assert_eq!(solver.stats().new_shapes, 1);
+
+        let child = &solver.shape_db()[2];
+        assert_eq!(child.parent, Some(1));
+        assert_eq!(child.group.len(), 1);
+        assert_eq!(child.solutions.as_ref().unwrap().len(), 2);
+        let (_, shape_id, _) = solver.cached_groups()[&(2, 0)];
+        assert_eq!(shape_id, 2);
+    }
+
+    #[test]
+    fn probe_merges_survivors_into_an_existing_child() {
+        // The domino at (6, 2) is decided at its top tile and leaves a child
+        // shape for its bottom one. The domino at (2, 2) only gets there by
+        // probing, since leaving its top tile inactive makes (0, 2) active
+        // next to a 0.
+        let mut solver = solver(&[
+            "00000000", //
+            "10000000", //
+            "52500250", //
+            "00500050", //
+            "00000000", //
+        ]);
+        solver.enable_probing();
+        solver.seed([(2, 2), (6, 2)]);
+        solver.propagate().unwrap();
+        assert_eq!(solver.map().get(6, 2), Tile::Active);
+        assert_eq!(solver.map().get(2, 2), Tile::Unprocessed);
+        let child = solver.shape_db()[2].clone();
+
+        assert_eq!(solver.probe().unwrap(), 1);
+        solver.propagate().unwrap();
+        assert_eq!(solver.map().get(2, 2), Tile::Active);
+        assert_eq!(solver.map().get(2, 3), Tile::Unprocessed);
+        assert_eq!(solver.map().get(0, 2), Tile::NotActive);
+        let stats = solver.stats();
+        assert_eq!((stats.probes, stats.refuted_probes), (4, 2));
+        assert_eq!(stats.new_shapes, 1);
+        assert_eq!(solver.shape_db()[2].used_solutions, child.used_solutions);
+        assert_eq!(solver.shape_db()[2].solutions, child.solutions);
+        let (_, shape_id, _) = solver.cached_groups()[&(2, 3)];
+        assert_eq!(shape_id, 2);
+    }
+
+    #[test]
+    fn probe_branches_leave_existing_children_as_they_were() {
+        // The domino at (2, 2) leaves a child shape for its bottom tile. The
+        // one at (5, 2) only does so in the probe of (7, 2) that makes it
+        // inactive at the top, which would add the solutions that leaves to
+        // that child.
+        let mut solver = solver(&[
+            "000000000", //
+            "025005250", //
+            "005005000", //
+            "000000000", //
+        ]);
+        solver.enable_probing();
+        solver.seed([(2, 1), (7, 1)]);
+        solver.propagate().unwrap();
+        assert_eq!(solver.map().get(7, 1), Tile::Unprocessed);
+        let map = rows_of(solver.map());
+        let child = solver.shape_db()[2].clone();
+        assert_eq!(child.used_solutions.as_ref().unwrap().len(), 2);
+
+        assert_eq!(solver.probe().unwrap(), 0);
+        assert_eq!(solver.stats().probes, 2);
+        assert_eq!(rows_of(solver.map()), map);
+        assert_eq!(solver.shape_db().len(), 3);
+        assert_eq!(solver.shape_db()[2].used_solutions, child.used_solutions);
+        assert_eq!(solver.shape_db()[2].solutions, child.solutions);
+    }
+
+    #[test]
+    fn undo_restores_trail_map_and_cache_exactly() {
+        let mut solver = solver(&[
+            "000000000", //
+            "025005250", //
+            "005005000", //
+            "000000000", //
+        ]);
+        solver.seed([(2, 1), (7, 1)]);
+        solver.propagate().unwrap();
+        let (mut map, mut shape_db, mut cached_groups) = solver.into_parts();
+        let mut shape_db_index = index_shape_db(&shape_db);
+        let mut memo = DeductionMemo::default();
+        let state = |map: &PackedMap, cached_groups: &CachedGroups, shape_db: &ShapeDb| {
+            let mut cached_groups = cached_groups.iter().collect::<Vec<_>>();
+            cached_groups.sort_unstable_by_key(|&(&position, _)| position);
+            (
+                rows_of(map),
+                format!("{cached_groups:?}"),
+                format!("{shape_db:?}"),
+            )
+        };
+        let before = state(&map, &cached_groups, &shape_db);
+
+        let mut map = TrailMap::new(&mut map);
+        let mut cached_groups = TrailCache::new(&mut cached_groups);
+        let mut branch = |map: &mut TrailMap<'_, PackedMap>,
+                          cached_groups: &mut TrailCache<'_>,
+                          shape_db: &mut ShapeDb,
+                          (x, y): (usize, usize),
+                          solution_id: usize| {
+            let placement = get_group(&*map, &shape_db_index, &mut *cached_groups, x, y);
+            let (anchor, shape_id, orientation) = placement;
+            let shape = shape_db[shape_id].clone();
+            try_solve(
+                map,
+                shape_id,
+                &shape,
+                &[solution_id],
+                shape_db,
+                &mut shape_db_index,
+                &mut *cached_groups,
+                anchor,
+                orientation,
+                &mut memo,
+            )
+            .unwrap();
+        };
+
+        // Making (7, 1) active extends the child shape of the domino at
+        // (2, 1) through the one at (5, 1).
+        branch(&mut map, &mut cached_groups, &mut shape_db, (7, 1), 1);
+        assert_eq!(map.get(5, 1), Tile::NotActive);
+        let (map_mark, cache_mark) = (map.mark(), cached_groups.mark());
+        let middle = state(map.map(), cached_groups.cached_groups(), &shape_db);
+        assert_ne!(middle, before);
+
+        branch(&mut map, &mut cached_groups, &mut shape_db, (2, 2), 0);
+        assert_eq!(map.get(2, 2), Tile::NotActive);
+        map.undo_to(map_mark);
+        cached_groups.undo_to(cache_mark, &mut shape_db, &mut memo);
+        assert_eq!(
+            state(map.map(), cached_groups.cached_groups(), &shape_db),
+            middle
+        );
+
+        map.undo_to(0);
+        cached_groups.undo_to(0, &mut shape_db, &mut memo);
+        assert_eq!(
+            state(map.map(), cached_groups.cached_groups(), &shape_db),
+            before
+        );
+    }
+
+    #[test]
+    fn probe_does_nothing_unless_enabled() {
+        let mut solver = solver(&["10000", "52500", "00500"]);
+        solver.seed([(2, 1)]);
+        solver.propagate().unwrap();
+        assert!(solver.frontier.is_none());
+        assert_eq!(solver.probe().unwrap(), 0);
+        assert_eq!(solver.stats().probes, 0);
+        assert_eq!(solver.map().get(2, 1), Tile::Unprocessed);
+    }
+
+    #[test]
+    fn commit_decided_merges_agreeing_solutions_into_an_existing_child() {
+        let mut solver = solver(&[
+            "00000000", //
+            "10000000", //
+            "52500250", //
+            "00500050", //
+            "00000000", //
+        ]);
+        solver.seed([(2, 2), (6, 2)]);
+        solver.propagate().unwrap();
+        let child = solver.shape_db()[2].clone();
+
+        let (mut map, mut shape_db, mut cached_groups) = solver.into_parts();
+        let mut shape_db_index = index_shape_db(&shape_db);
+        let decided = HashMap::from([((2, 2), Tile::Active)]);
+        let committed = commit_decided(
+            &mut map,
+            &decided,
+            &mut shape_db,
+            &mut shape_db_index,
+            &mut cached_groups,
+        )
+        .unwrap();
+        assert_eq!(committed, 1);
+        assert_eq!(map.get(2, 2), Tile::Active);
+        assert_eq!(map.get(2, 3), Tile::Unprocessed);
+        assert_eq!(shape_db.len(), 3);
+        assert_eq!(shape_db[2].used_solutions, child.used_solutions);
+        assert_eq!(cached_groups[&(2, 3)].1, 2);
+    }
+
+    #[test]
+    fn commit_decided_reports_decisions_that_do_not_fit() {
+        let mut map = map_from_rows(&["2500"]);
+        let mut shape_db = shape_db();
+        let mut shape_db_index = index_shape_db(&shape_db);
+        let decided = HashMap::from([((1, 0), Tile::NotActive)]);
+        let error = commit_decided(
+            &mut map,
+            &decided,
+            &mut shape_db,
+            &mut shape_db_index,
+            &mut CachedGroups::new(),
+        )
+        .unwrap_err();
+        assert_eq!(error.origin, (1, 0));
+        assert_eq!(map.get(1, 0), Tile::Unprocessed);
+    }
+
+    #[test]
+    fn labels_find_the_same_groups_as_flood_fill() {
+        let rows = [
+            "00000000", //
+            "10000000", //
+            "52500250", //
+            "00500050", //
+            "00000000", //
+        ];
+        let labels = Labels::compute(&map_from_rows(&rows));
+        let mut results = Vec::new();
+        for labels in [None, Some(&labels)] {
+            let mut solver = solver(&rows);
+            if let Some(labels) = labels {
+                solver.use_labels(labels);
+            }
+            solver.enable_probing();
+            solver.seed([(2, 2), (6, 2)]);
+            solver.propagate().unwrap();
+            while solver.probe().unwrap() > 0 {
+                solver.propagate().unwrap();
+            }
+            let stats = solver.stats();
+            let mut cached_groups = solver.cached_groups().iter().collect::<Vec<_>>();
+            cached_groups.sort_unstable_by_key(|&(&position, _)| position);
+            results.push((
+                rows_of(solver.map()),
+                format!("{cached_groups:?}"),
+                (stats.solved_groups, stats.probes, stats.new_shapes),
+            ));
+        }
+        assert_eq!(results[0], results[1]);
+    }
+
+    #[test]
+    fn step_reports_the_clue_that_rules_out_every_solution() {
+        let mut solver = solver(&["0000", "2510", "0000"]);
+        solver.seed([(1, 1)]);
+        let error = solver.propagate().unwrap_err();
+        assert_eq!(error.origin, (1, 1));
+        assert_eq!(error.shape_id(), 0);
+        assert_eq!(error.rejections.len(), 2);
+        assert!(error.clue().is_some());
+        assert_eq!(solver.map().get(1, 1), Tile::Unprocessed);
+    }
+}
@@ -0,0 +1,147 @@
+/// A tile of the map, stored as one nibble.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(into = "u8", try_from = "u8")]
+#[repr(u8)]
+pub enum Tile {
+    Empty = 0,
+    /// A clue that needs no more active neighbors.
+    Clue0 = 1,
+    /// A clue that needs one more active neighbor.
+    Clue1 = 2,
+    /// A clue that needs two more active neighbors.
+    Clue2 = 3,
+    /// A tile that is not known to be active or not yet.
+    Unprocessed = 5,
+    NotActive = 6,
+    Active = 7,
+    /// Not part of the puzzle, only used to mark tiles in images.
+    Marked = 8,
+    /// Shown as `?` in the puzzle.
+    Unknown = 10,
+}
+
+/// A value that is not one of the [`Tile`]s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidTile(pub u8);
+
+impl std::fmt::Display for InvalidTile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid tile value {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidTile {}
+
+impl TryFrom<u8> for Tile {
+    type Error = InvalidTile;
+
+    fn try_from(value: u8) -> Result<Self, InvalidTile> {
+        Ok(match value {
+            0 => Tile::Empty,
+            1 => Tile::Clue0,
+            2 => Tile::Clue1,
+            3 => Tile::Clue2,
+            5 => Tile::Unprocessed,
+            6 => Tile::NotActive,
+            7 => Tile::Active,
+            8 => Tile::Marked,
+            10 => Tile::Unknown,
+            _ => return Err(InvalidTile(value)),
+        })
+    }
+}
+
+impl From<Tile> for u8 {
+    fn from(tile: Tile) -> u8 {
+        tile as u8
+    }
+}
+
+impl Tile {
+    /// Whether every nibble of `byte` is a valid tile. Used to validate
+    /// packed maps a byte at a time.
+    pub(crate) fn is_valid_pair(byte: u8) -> bool {
+        Tile::try_from(byte >> 4).is_ok() && Tile::try_from(byte & 0x0F).is_ok()
+    }
+
+    /// Decodes a nibble of a map that was validated when it was loaded.
+    pub(crate) fn from_nibble(nibble: u8) -> Tile {
+        Tile::try_from(nibble).unwrap_or_else(|_| unreachable!("invalid tile {nibble} in map"))
+    }
+
+    /// The clue that needs `remaining` more active neighbors.
+    pub fn clue(remaining: u8) -> Tile {
+        match remaining {
+            0 => Tile::Clue0,
+            1 => Tile::Clue1,
+            2 => Tile::Clue2,
+            _ => panic!("A clue needs at most 2 more active neighbors, not {remaining}"),
+        }
+    }
+
+    pub fn is_clue(self) -> bool {
+        self.clue_remaining().is_some()
+    }
+
+    /// How many more active neighbors a clue needs.
+    pub fn clue_remaining(self) -> Option<u8> {
+        match self {
+            Tile::Clue0 => Some(0),
+            Tile::Clue1 => Some(1),
+            Tile::Clue2 => Some(2),
+            _ => None,
+        }
+    }
+
+    /// Whether the tile is solved, one way or the other.
+    pub fn is_decided(self) -> bool {
+        matches!(self, Tile::Active | Tile::NotActive)
+    }
+
+    /// The character `show` prints for the tile.
+    pub fn glyph(self) -> char {
+        match self {
+            Tile::Empty => ' ',
+            Tile::Clue0 => '0',
+            Tile::Clue1 => '1',
+            Tile::Clue2 => '2',
+            Tile::Unprocessed | Tile::Active => '#',
+            Tile::NotActive => '.',
+            Tile::Marked => '*',
+            Tile::Unknown => '?',
+        }
+    }
+
+    /// Like [`Tile::glyph`], but for output without colors, where active
+    /// and unprocessed tiles would look the same.
+    pub fn plain_glyph(self) -> char {
+        match self {
+            Tile::Active => '@',
+            _ => self.glyph(),
+        }
+    }
+
+    /// The ANSI style `show` prints the glyph in, if any.
+    pub fn ansi_style(self) -> Option<&'static str> {
+        match self {
+            Tile::NotActive => Some("37;2"),
+            Tile::Active => Some("33;1"),
+            _ => None,
+        }
+    }
+
+    /// The color of the tile in rendered images.
+    pub fn color(self) -> [u8; 3] {
+        match self {
+            Tile::Empty => [0, 0, 0],
+            Tile::Clue0 => [255, 0, 0],
+            Tile::Clue1 => [255, 255, 0],
+            Tile::Clue2 => [0, 255, 0],
+            Tile::Unprocessed => [255, 255, 255],
+            Tile::NotActive => [0, 0, 255],
+            Tile::Active => [128, 128, 128],
+            Tile::Marked => [231, 141, 14],
+            Tile::Unknown => [255, 0, 255],
+        }
+    }
+}